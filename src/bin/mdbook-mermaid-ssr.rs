@@ -1,10 +1,13 @@
 use anyhow::bail;
 use clap::{Parser, Subcommand};
 use mdbook_mermaid_ssr::{Mermaid, config::Config};
-use mdbook_preprocessor::Preprocessor;
 use mdbook_preprocessor::errors::Error;
+use mdbook_preprocessor::{Preprocessor, PreprocessorContext};
 
 use std::io;
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(
@@ -24,6 +27,15 @@ enum Commands {
         /// The renderer to check support for
         renderer: String,
     },
+    /// Keep a browser running and render diagrams for preprocessor runs of the book
+    ///
+    /// Useful with `mdbook serve`, where every rebuild would otherwise launch a new browser.
+    #[cfg(unix)]
+    Daemon {
+        /// The root directory of the book
+        #[arg(long, default_value = ".")]
+        book: PathBuf,
+    },
 }
 
 fn main() -> anyhow::Result<()> {
@@ -33,6 +45,8 @@ fn main() -> anyhow::Result<()> {
 
     match cli.command {
         Some(Commands::Supports { renderer }) => handle_supports(&renderer),
+        #[cfg(unix)]
+        Some(Commands::Daemon { book }) => handle_daemon(&book),
         None => handle_preprocessing(),
     }
 }
@@ -50,8 +64,7 @@ fn handle_preprocessing() -> Result<(), Error> {
     }
 
     let config = Config::from_context(&ctx);
-    let preprocessor = Mermaid::new(config)
-        .map_err(|e| Error::msg(format!("Failed to initialize mermaid preprocessor: {e}")))?;
+    let preprocessor = Mermaid::new(config);
     let processed_book = preprocessor.run(&ctx, book)?;
     serde_json::to_writer(io::stdout(), &processed_book)?;
 
@@ -59,13 +72,83 @@ fn handle_preprocessing() -> Result<(), Error> {
 }
 
 fn handle_supports(renderer: &str) -> anyhow::Result<()> {
-    // For the supports check, we don't need to parse config, just check if we can initialize
-    let Ok(preprocessor) = Mermaid::new(Config::default()) else {
-        bail!("can't mermaid renderer for given renderer");
-    };
-    let supported = preprocessor.supports_renderer(renderer).is_ok_and(|s| s);
+    // Answering this must not launch a browser, which creating the preprocessor does not do
+    let preprocessor = Mermaid::new(book_config(Path::new("."))?);
+    // A rejected configuration is reported instead of silently skipping the preprocessor
+    let supported = preprocessor.supports_renderer(renderer)?;
     if !supported {
-        bail!("renderer not supported, but chrome can be initialized, so this could be possible");
+        bail!("renderer {renderer} is not supported");
     }
     Ok(())
 }
+
+/// The configuration of the preprocessor in the book at `book`
+///
+/// Without a `book.toml`, the defaults are used. mdbook runs `supports` in the book root, but
+/// does not pass the configuration.
+fn book_config(book: &Path) -> anyhow::Result<Config> {
+    let path = book.join("book.toml");
+    let book_config = if path.exists() {
+        mdbook_preprocessor::config::Config::from_disk(path)?
+    } else {
+        mdbook_preprocessor::config::Config::default()
+    };
+    let ctx = PreprocessorContext::new(book.to_path_buf(), book_config, "html".to_string());
+    Ok(Config::from_context(&ctx))
+}
+
+#[cfg(unix)]
+fn handle_daemon(book: &Path) -> anyhow::Result<()> {
+    let config = daemon_config(book)?;
+    mdbook_mermaid_ssr::daemon::serve(&config.daemon_socket(), &config)
+}
+
+/// The configuration of the daemon for the book at `book`
+///
+/// mdbook passes an absolute book root to the preprocessor, so a relative `book` must be resolved
+/// the same way. Otherwise the page setups differ and the daemon turns away every request.
+#[cfg(unix)]
+fn daemon_config(book: &Path) -> anyhow::Result<Config> {
+    book_config(&book.canonicalize()?)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::path::{Component, PathBuf};
+
+    use mdbook_mermaid_ssr::config::Config;
+    use mdbook_preprocessor::PreprocessorContext;
+
+    use super::daemon_config;
+
+    #[test]
+    fn daemon_accepts_preprocessor_config() {
+        let book = tempfile::tempdir().unwrap();
+        std::fs::write(
+            book.path().join("book.toml"),
+            "[preprocessor.mermaid-ssr]\ntheme = \"forest\"\n",
+        )
+        .unwrap();
+        // The same directory, relative to the current one
+        let cwd = std::env::current_dir().unwrap();
+        let relative: PathBuf = cwd
+            .components()
+            .skip(1)
+            .map(|_| Component::ParentDir)
+            .chain(book.path().canonicalize().unwrap().components().skip(1))
+            .collect();
+        assert!(relative.is_relative());
+
+        // mdbook gives the preprocessor the absolute book root
+        let root = book.path().canonicalize().unwrap();
+        let book_config =
+            mdbook_preprocessor::config::Config::from_disk(root.join("book.toml")).unwrap();
+        let ctx = PreprocessorContext::new(root, book_config, "html".to_string());
+        let preprocessor = Config::from_context(&ctx);
+
+        // The preprocessor has to find the daemon, and the daemon has to accept its requests
+        let daemon = daemon_config(&relative).unwrap();
+        assert_eq!(preprocessor.daemon_socket(), daemon.daemon_socket());
+        assert_eq!(preprocessor.build_page_setup(), daemon.build_page_setup());
+    }
+}
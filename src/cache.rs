@@ -0,0 +1,219 @@
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::renderer::MERMAID_JS;
+
+/// File extension of cache entries.
+const EXTENSION: &str = "svg";
+
+/// Content-addressed on-disk cache for rendered diagrams
+///
+/// Entries are keyed by the SHA-256 of the diagram source, within a namespace directory named by
+/// the SHA-256 of the configuration fingerprint and the bundled mermaid.js. Changing any of them
+/// results in a cache miss, and books or configurations sharing a cache directory do not evict
+/// each other's entries.
+#[derive(Debug)]
+pub struct Cache {
+    /// Directory of the namespace within the cache directory
+    dir: PathBuf,
+    used: Mutex<HashSet<String>>,
+}
+
+impl Cache {
+    /// Opens the cache in `dir`
+    ///
+    /// The directory is only created once the first entry is stored.
+    ///
+    /// # Arguments
+    /// * `dir` - Directory the rendered diagrams are stored in
+    /// * `fingerprint` - Everything besides the diagram source which influences the rendered SVG,
+    ///   such as the page setup produced by [`crate::config::Config::build_page_setup`]
+    pub fn open(dir: impl Into<PathBuf>, fingerprint: &str) -> Self {
+        let namespace = digest(&[MERMAID_JS, fingerprint]);
+        Self {
+            dir: Into::<PathBuf>::into(dir).join(namespace),
+            used: Mutex::default(),
+        }
+    }
+
+    /// The directory the entries of this cache are stored in
+    #[must_use]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn key(source: &str) -> String {
+        digest(&[source])
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.{EXTENSION}"))
+    }
+
+    fn mark_used(&self, key: String) {
+        self.used
+            .lock()
+            .expect("cache bookkeeping is never poisoned")
+            .insert(key);
+    }
+
+    /// Looks up the rendered SVG for `source`
+    pub fn get(&self, source: &str) -> Option<String> {
+        let key = Self::key(source);
+        let svg = fs::read_to_string(self.path(&key)).ok()?;
+        self.mark_used(key);
+        Some(svg)
+    }
+
+    /// Stores the rendered SVG for `source`
+    pub fn put(&self, source: &str, svg: &str) -> Result<()> {
+        let key = Self::key(source);
+        let path = self.path(&key);
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create cache directory {}", self.dir.display()))?;
+        // Write to a temporary file first, so that an interrupted build never leaves a truncated entry behind
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, svg).with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Failed to write {}", path.display()))?;
+        self.mark_used(key);
+        Ok(())
+    }
+
+    /// Removes all entries of this namespace which were not used since the cache was opened
+    ///
+    /// Entries of other namespaces are left alone. Returns the number of evicted entries.
+    pub fn evict_unused(&self) -> Result<usize> {
+        let used = self
+            .used
+            .lock()
+            .expect("cache bookkeeping is never poisoned");
+        if !self.dir.exists() {
+            return Ok(0);
+        }
+        let mut evicted = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != EXTENSION) {
+                continue;
+            }
+            let key = path.file_stem().and_then(|stem| stem.to_str());
+            if key.is_some_and(|key| used.contains(key)) {
+                continue;
+            }
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to evict {}", path.display()))?;
+            evicted += 1;
+        }
+        Ok(evicted)
+    }
+}
+
+/// The hex encoded SHA-256 of `parts`
+///
+/// Each part is prefixed by its length, so that moving bytes between parts changes the digest.
+pub(crate) fn digest(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    let mut hex = String::with_capacity(64);
+    for byte in hasher.finalize() {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open(dir.path(), "init");
+        assert_eq!(cache.get("graph TD\nA-->B"), None);
+        cache.put("graph TD\nA-->B", "<svg></svg>").unwrap();
+        assert_eq!(
+            cache.get("graph TD\nA-->B"),
+            Some("<svg></svg>".to_string())
+        );
+    }
+
+    #[test]
+    fn fingerprint_is_part_of_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        Cache::open(dir.path(), "dark")
+            .put("graph TD\nA-->B", "<svg></svg>")
+            .unwrap();
+        let cache = Cache::open(dir.path(), "forest");
+        assert_eq!(cache.get("graph TD\nA-->B"), None);
+    }
+
+    #[test]
+    fn evicts_unused_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open(dir.path(), "init");
+        cache.put("graph TD\nA-->B", "<svg>old</svg>").unwrap();
+        cache.put("graph TD\nA-->C", "<svg>kept</svg>").unwrap();
+
+        let cache = Cache::open(dir.path(), "init");
+        assert!(cache.get("graph TD\nA-->C").is_some());
+        assert_eq!(cache.evict_unused().unwrap(), 1);
+
+        let cache = Cache::open(dir.path(), "init");
+        assert_eq!(cache.get("graph TD\nA-->B"), None);
+        assert_eq!(
+            cache.get("graph TD\nA-->C"),
+            Some("<svg>kept</svg>".to_string())
+        );
+    }
+
+    #[test]
+    fn does_not_evict_other_namespaces() {
+        let dir = tempfile::tempdir().unwrap();
+        let dark = Cache::open(dir.path(), "dark");
+        dark.put("graph TD\nA-->B", "<svg>dark</svg>").unwrap();
+        // Not part of the cache at all
+        fs::write(dir.path().join("0123456789abcdef.svg"), "<svg/>").unwrap();
+
+        let forest = Cache::open(dir.path(), "forest");
+        forest.put("graph TD\nA-->B", "<svg>forest</svg>").unwrap();
+        assert_eq!(forest.evict_unused().unwrap(), 0);
+
+        let dark = Cache::open(dir.path(), "dark");
+        assert_eq!(
+            dark.get("graph TD\nA-->B"),
+            Some("<svg>dark</svg>".to_string())
+        );
+        assert!(dir.path().join("0123456789abcdef.svg").exists());
+    }
+
+    #[test]
+    fn test_digest() {
+        assert_eq!(
+            digest(&["abc"]),
+            "ce91dc5eec0139adf091900d225971d6ad246a845bad791b5693a9d0d55dd391"
+        );
+        assert_ne!(digest(&["ab", "c"]), digest(&["a", "bc"]));
+    }
+
+    #[test]
+    fn directory_is_created_lazily() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open(dir.path().join("cache"), "init");
+        assert_eq!(cache.get("graph TD\nA-->B"), None);
+        assert_eq!(cache.evict_unused().unwrap(), 0);
+        assert!(!cache.dir().exists());
+
+        cache.put("graph TD\nA-->B", "<svg></svg>").unwrap();
+        assert!(cache.dir().exists());
+    }
+}
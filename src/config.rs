@@ -1,24 +1,173 @@
 use mdbook_preprocessor::PreprocessorContext;
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, time::Duration};
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    path::PathBuf,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_RENDER_RETRIES: u32 = 2;
+pub const DEFAULT_SERVER_RETRIES: u32 = 2;
+pub const DEFAULT_DEVICE_SCALE_FACTOR: f64 = 1.0;
+pub const DEFAULT_CACHE_DIR: &str = ".mermaid-cache";
+pub const DEFAULT_DAEMON_SOCKET: &str = ".mermaid-ssr.sock";
 
 /// Configuration for the mermaid-ssr preprocessor
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all(serialize = "camelCase", deserialize = "kebab-case"))]
 pub struct Config {
     /// Timeout for rendering operations
+    ///
+    /// Used for every timeout which is not configured separately.
     #[serde(default = "default_timeout", with = "humantime_serde")]
     pub timeout: Duration,
 
+    /// Timeout for launching the browser and loading mermaid into a tab
+    #[serde(default, with = "humantime_serde")]
+    pub launch_timeout: Option<Duration>,
+
+    /// Timeout for rendering a single diagram, after which its rendering is cancelled
+    #[serde(default, with = "humantime_serde")]
+    pub render_timeout: Option<Duration>,
+
+    /// Timeout after which an unresponsive browser is considered dead
+    ///
+    /// It is extended to cover the render timeout, so that a browser which is still rendering is
+    /// not considered dead.
+    #[serde(default, with = "humantime_serde")]
+    pub idle_timeout: Option<Duration>,
+
     /// How to handle rendering errors
     #[serde(default)]
     pub on_error: ErrorHandling,
 
+    /// How to handle diagrams which exceeded `render-timeout`, defaults to `on-error`
+    pub on_timeout: Option<ErrorHandling>,
+
     /// Custom path to Chrome/Chromium executable
     pub chrome_path: Option<PathBuf>,
 
+    /// Additional command line arguments for the launched Chrome/Chromium
+    #[serde(default)]
+    pub chrome_args: Vec<String>,
+
+    /// Whether the launched Chrome/Chromium runs sandboxed
+    ///
+    /// Disabling the sandbox is required in some containers, for example when running as root.
+    #[serde(default = "default_sandbox")]
+    pub sandbox: bool,
+
+    /// Websocket debugging URL of an already running browser to render in, instead of launching one
+    pub browser_ws_url: Option<String>,
+
+    /// Remote debugging port of an already running browser on this machine to render in
+    ///
+    /// Ignored if `browser-ws-url` is set.
+    pub remote_debugging_port: Option<u16>,
+
+    /// How often rendering a diagram is retried after the browser or its tab failed
+    ///
+    /// The browser is relaunched if it crashed. Syntax errors and timeouts are not retried.
+    #[serde(default = "default_render_retries")]
+    pub render_retries: u32,
+
+    /// When a browser tab is replaced by a fresh one, to bound the memory mermaid leaves behind
+    #[serde(default)]
+    pub recycle_after: RecycleAfter,
+
+    /// Which backend renders the diagrams
+    #[serde(default)]
+    pub backend: Backend,
+
+    /// Custom path to the mermaid-cli (`mmdc`) executable, used by [`Backend::Mmdc`]
+    pub mmdc_path: Option<PathBuf>,
+
+    /// Base URL of the Kroki-compatible rendering service, used by [`Backend::Kroki`]
+    pub server_url: Option<String>,
+
+    /// Timeout for requests to the rendering service, defaults to `render-timeout`
+    #[serde(default, with = "humantime_serde")]
+    pub server_timeout: Option<Duration>,
+
+    /// How often a failed request to the rendering service is retried
+    ///
+    /// Diagrams which the service rejects, for example due to syntax errors, are not retried.
+    #[serde(default = "default_server_retries")]
+    pub server_retries: u32,
+
+    /// Whether the rendered diagrams are identical across builds and machines
+    ///
+    /// Each diagram is seeded by its position in the book, unless the seeds are configured
+    /// explicitly, and `Math.random` is replaced by a seeded generator in the browser.
+    #[serde(default)]
+    pub reproducible: bool,
+
+    /// The time the clock of the page is frozen at, such as `2024-01-01T00:00:00Z`
+    ///
+    /// Diagrams relative to today, such as Gantt charts, render identically until it changes.
+    /// Defaults to `SOURCE_DATE_EPOCH`, see [`Self::render_date()`].
+    #[serde(default, with = "humantime_serde")]
+    pub render_date: Option<SystemTime>,
+
+    /// The IANA timezone the page is emulated in, such as `Europe/Berlin`
+    ///
+    /// Defaults to the timezone of the host.
+    pub timezone: Option<String>,
+
+    /// The locale the page is emulated in, such as `de-DE`
+    ///
+    /// Defaults to the locale of the host.
+    pub locale: Option<String>,
+
+    /// HTTP(S) URL prefixes the page may load resources from
+    ///
+    /// All other network access of the page, such as images referenced by diagrams in `loose`
+    /// security level, is blocked and logged. A URL matches an entry with the same scheme, host
+    /// and port, whose path is the path of the entry or lies below it.
+    #[serde(default)]
+    pub allow_network: Vec<String>,
+
+    /// How rendered diagrams are embedded into the chapters for the `html` renderer
+    #[serde(default)]
+    pub output: Output,
+
+    /// How diagrams are embedded into the chapters for other renderers, by renderer name
+    ///
+    /// Renderers which are not listed leave the diagrams untouched, see [`Self::output_for()`].
+    #[serde(default)]
+    pub renderers: BTreeMap<String, Output>,
+
+    /// SVGs smaller than this many bytes are inlined even if `output` is `file` or `png`
+    #[serde(default)]
+    pub inline_threshold: usize,
+
+    /// Number of PNG pixels per CSS pixel of the diagram, if `output` is `png` or `picture`
+    #[serde(default = "default_device_scale_factor")]
+    pub device_scale_factor: f64,
+
+    /// Whether rendered SVGs are re-serialized as well-formed XML, see [`crate::svg::to_xml()`]
+    ///
+    /// Defaults to whether the renderer is `epub`, see [`Self::xhtml_for()`].
+    pub xhtml: Option<bool>,
+
+    /// Whether rendered diagrams are cached on disk
+    #[serde(default = "default_cache")]
+    pub cache: bool,
+
+    /// Directory rendered diagrams are cached in, relative to the book root
+    pub cache_dir: Option<PathBuf>,
+
+    /// Number of chapters whose diagrams are rendered concurrently, each in its own browser tab
+    ///
+    /// Defaults to the available parallelism of the machine.
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Unix socket of the render daemon, relative to the book root
+    pub daemon_socket: Option<PathBuf>,
+
     /// Mermaid configuration options (will be passed to `mermaid.initialize({..})`)
     #[serde(flatten)]
     pub mermaid: MermaidConfig,
@@ -28,8 +177,37 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             timeout: DEFAULT_TIMEOUT,
+            launch_timeout: None,
+            render_timeout: None,
+            idle_timeout: None,
             on_error: ErrorHandling::default(),
+            on_timeout: None,
             chrome_path: None,
+            chrome_args: vec![],
+            sandbox: true,
+            browser_ws_url: None,
+            remote_debugging_port: None,
+            render_retries: DEFAULT_RENDER_RETRIES,
+            recycle_after: RecycleAfter::default(),
+            backend: Backend::default(),
+            mmdc_path: None,
+            server_url: None,
+            server_timeout: None,
+            server_retries: DEFAULT_SERVER_RETRIES,
+            reproducible: false,
+            render_date: None,
+            timezone: None,
+            locale: None,
+            allow_network: vec![],
+            output: Output::Inline,
+            renderers: BTreeMap::new(),
+            inline_threshold: 0,
+            device_scale_factor: DEFAULT_DEVICE_SCALE_FACTOR,
+            xhtml: None,
+            cache: true,
+            cache_dir: None,
+            jobs: None,
+            daemon_socket: None,
             mermaid: MermaidConfig::default(),
         }
     }
@@ -39,6 +217,64 @@ fn default_timeout() -> Duration {
     DEFAULT_TIMEOUT
 }
 
+fn default_sandbox() -> bool {
+    true
+}
+
+fn default_render_retries() -> u32 {
+    DEFAULT_RENDER_RETRIES
+}
+
+fn default_server_retries() -> u32 {
+    DEFAULT_SERVER_RETRIES
+}
+
+/// Seeds `Math.random` with a hash of the diagram ID before each render, see
+/// <https://github.com/bryc/code/blob/master/jshash/PRNGs.md#mulberry32>
+const SEEDED_RANDOM_SCRIPT: &str = r"let randomState = 0;
+window.seedRandom = function(seed) {
+    randomState = 0;
+    for (let i = 0; i < seed.length; i++) {
+        randomState = Math.imul(randomState ^ seed.charCodeAt(i), 2654435761);
+    }
+};
+Math.random = function() {
+    randomState = (randomState + 0x6D2B79F5) | 0;
+    let t = Math.imul(randomState ^ (randomState >>> 15), 1 | randomState);
+    t = (t + Math.imul(t ^ (t >>> 7), 61 | t)) ^ t;
+    return ((t ^ (t >>> 14)) >>> 0) / 4294967296;
+};";
+
+/// Parses the seconds since the Unix epoch, as specified by
+/// <https://reproducible-builds.org/specs/source-date-epoch/>
+fn parse_source_date_epoch(epoch: &str) -> anyhow::Result<SystemTime> {
+    let seconds: u64 = epoch
+        .trim()
+        .parse()
+        .map_err(|e| anyhow::anyhow!("{epoch:?} is not a number of seconds: {e}"))?;
+    Ok(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+fn default_device_scale_factor() -> f64 {
+    DEFAULT_DEVICE_SCALE_FACTOR
+}
+
+fn default_cache() -> bool {
+    true
+}
+
+/// Limits after which a browser tab is replaced by a fresh one
+///
+/// Without any limit, tabs are kept for the whole build.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RecycleAfter {
+    /// Number of diagrams rendered in the tab
+    pub renders: Option<NonZeroUsize>,
+    /// JS heap usage of the tab, in megabytes
+    pub heap_mb: Option<u32>,
+}
+
 /// Mermaid initialization options
 /// See: <https://mermaid.js.org/config/setup/modules/mermaidAPI.html#mermaidapi-configuration-defaults>
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -78,19 +314,107 @@ impl Config {
     pub fn from_context(ctx: &PreprocessorContext) -> Self {
         const NAME: &str = "mermaid-ssr";
 
-        ctx.config
+        let mut config = ctx
+            .config
             .preprocessors::<Config>()
             .ok()
             .and_then(|mut map| map.remove(NAME))
             .unwrap_or_else(|| {
                 log::debug!("No configuration found for {NAME}. Using defaults.");
                 Config::default()
-            })
+            });
+        // Paths are relative to the book root, not to wherever mdbook was invoked from
+        config.cache_dir = config.cache_dir().map(|dir| ctx.root.join(dir));
+        config.daemon_socket = Some(ctx.root.join(config.daemon_socket()));
+        config
     }
 
-    /// Build the mermaid initialization script with all configured options
+    /// Timeout for launching the browser and loading mermaid into a tab
     #[must_use]
-    pub fn build_mermaid_init_script(&self) -> String {
+    pub fn launch_timeout(&self) -> Duration {
+        self.launch_timeout.unwrap_or(self.timeout)
+    }
+
+    /// Timeout for rendering a single diagram
+    #[must_use]
+    pub fn render_timeout(&self) -> Duration {
+        self.render_timeout.unwrap_or(self.timeout)
+    }
+
+    /// Timeout after which an unresponsive browser is considered dead
+    #[must_use]
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout.unwrap_or(self.timeout)
+    }
+
+    /// How to handle diagrams which exceeded the render timeout
+    #[must_use]
+    pub fn on_timeout(&self) -> ErrorHandling {
+        self.on_timeout.unwrap_or(self.on_error)
+    }
+
+    /// How diagrams are embedded into the chapters for `renderer`
+    ///
+    /// An entry in `renderers` takes precedence. Otherwise, `html` uses `output`, and all other
+    /// renderers pass the diagrams through.
+    #[must_use]
+    pub fn output_for(&self, renderer: &str) -> Output {
+        match self.renderers.get(renderer) {
+            Some(&output) => output,
+            None if renderer == "html" => self.output,
+            None => Output::Passthrough,
+        }
+    }
+
+    /// Whether rendered SVGs are re-serialized as well-formed XML for `renderer`
+    ///
+    /// EPUB chapters are XHTML, which readers reject if it is not well-formed.
+    #[must_use]
+    pub fn xhtml_for(&self, renderer: &str) -> bool {
+        self.xhtml.unwrap_or(renderer == "epub")
+    }
+
+    /// Whether any renderer embeds diagrams as PNGs
+    #[must_use]
+    pub fn rasterizes(&self) -> bool {
+        [self.output]
+            .iter()
+            .chain(self.renderers.values())
+            .any(|output| matches!(output, Output::Png | Output::Picture))
+    }
+
+    /// The directory rendered diagrams are cached in, if caching is enabled
+    #[must_use]
+    pub fn cache_dir(&self) -> Option<PathBuf> {
+        if !self.cache {
+            return None;
+        }
+        Some(
+            self.cache_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_CACHE_DIR)),
+        )
+    }
+
+    /// The Unix socket the render daemon listens on
+    #[must_use]
+    pub fn daemon_socket(&self) -> PathBuf {
+        self.daemon_socket
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_DAEMON_SOCKET))
+    }
+
+    /// The number of diagrams rendered concurrently
+    #[must_use]
+    pub fn jobs(&self) -> usize {
+        self.jobs
+            .or_else(|| thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
+    }
+
+    /// Build the mermaid configuration with all configured options, as passed to `mermaid.initialize({..})`
+    #[must_use]
+    pub fn build_mermaid_config_json(&self) -> String {
         // Clone and convert additional kebab-case keys to camelCase
         let mut mermaid_config = self.mermaid.clone();
         let additional: serde_json::Map<String, serde_json::Value> = mermaid_config
@@ -100,25 +424,182 @@ impl Config {
             .collect();
         mermaid_config.additional = additional;
 
-        let config_json =
-            serde_json::to_string(&mermaid_config).expect("Failed to serialize mermaid config");
+        serde_json::to_string(&mermaid_config).expect("Failed to serialize mermaid config")
+    }
+
+    /// The time the clock of the page is frozen at, if any
+    ///
+    /// Falls back to the `SOURCE_DATE_EPOCH` environment variable if `render-date` is not set.
+    #[must_use]
+    pub fn render_date(&self) -> Option<SystemTime> {
+        self.render_date.or_else(|| {
+            let epoch = std::env::var("SOURCE_DATE_EPOCH").ok()?;
+            parse_source_date_epoch(&epoch)
+                .inspect_err(|e| log::warn!("Ignoring SOURCE_DATE_EPOCH: {e}"))
+                .ok()
+        })
+    }
+
+    /// Build the script which sets up the page before mermaid.js is loaded
+    #[must_use]
+    pub fn build_prelude_script(&self) -> String {
+        let mut prelude = vec![];
+        if self.reproducible {
+            prelude.push(SEEDED_RANDOM_SCRIPT.to_string());
+        }
+        if let Some(date) = self.render_date() {
+            let now = match date.duration_since(UNIX_EPOCH) {
+                Ok(since) => since.as_millis().to_string(),
+                Err(e) => format!("-{}", e.duration().as_millis()),
+            };
+            // Only `new Date()` and `Date.now()` are frozen, explicit dates are left alone
+            prelude.push(format!(
+                r"{{
+    const now = {now};
+    const RealDate = Date;
+    const FrozenDate = function(...args) {{
+        if (!new.target) {{
+            return new RealDate(now).toString();
+        }}
+        return new RealDate(...(args.length === 0 ? [now] : args));
+    }};
+    FrozenDate.prototype = RealDate.prototype;
+    Object.setPrototypeOf(FrozenDate, RealDate);
+    FrozenDate.now = () => now;
+    window.Date = FrozenDate;
+}}"
+            ));
+        }
+        prelude.join("\n")
+    }
+
+    /// Everything the page diagrams are rendered in is set up with, besides mermaid.js itself
+    ///
+    /// Pages with the same setup render identical SVGs.
+    #[must_use]
+    pub fn build_page_setup(&self) -> String {
+        let mut setup = vec![
+            self.build_prelude_script(),
+            self.build_mermaid_init_script(),
+        ];
+        if let Some(timezone) = &self.timezone {
+            setup.push(format!("// timezone: {timezone}"));
+        }
+        if let Some(locale) = &self.locale {
+            setup.push(format!("// locale: {locale}"));
+        }
+        for allowed in &self.allow_network {
+            setup.push(format!("// allow-network: {allowed}"));
+        }
+        setup.join("\n")
+    }
+
+    /// Build the `%%{init: ...}%%` directive which seeds a diagram for reproducible builds
+    ///
+    /// Returns `None` if builds are not reproducible, or all seeds are configured explicitly.
+    #[must_use]
+    pub fn build_seed_directive(&self, seed: u32) -> Option<String> {
+        if !self.reproducible {
+            return None;
+        }
+        let configured = |option: &str| {
+            self.mermaid
+                .additional
+                .keys()
+                .any(|key| kebab_to_camel(key) == option)
+        };
+        let mut seeds = serde_json::Map::new();
+        if !configured("deterministicIds") {
+            seeds.insert("deterministicIds".to_string(), true.into());
+        }
+        if !configured("deterministicIDSeed") {
+            seeds.insert("deterministicIDSeed".to_string(), seed.to_string().into());
+        }
+        if !configured("handDrawnSeed") {
+            seeds.insert("handDrawnSeed".to_string(), seed.into());
+        }
+        (!seeds.is_empty()).then(|| format!("%%{{init: {}}}%%", serde_json::Value::Object(seeds)))
+    }
+
+    /// Build the mermaid initialization script with all configured options
+    #[must_use]
+    pub fn build_mermaid_init_script(&self) -> String {
+        let config_json = self.build_mermaid_config_json();
 
         format!(
             r"mermaid.initialize({config_json});
 
-window.render = async function(id, code) {{
+window.render = async function(id, code, timeout) {{
+    // Only set up for reproducible builds
+    window.seedRandom?.(id);
+    let timer;
+    const timedOut = new Promise((resolve) => {{
+        timer = setTimeout(() => resolve({{ timedOut: true }}), timeout);
+    }});
     try {{
-        const {{ svg }} = await mermaid.render(id, code);
-        return svg;
+        const result = await Promise.race([mermaid.render(id, code), timedOut]);
+        return result.timedOut ? result : result.svg;
     }} catch (error) {{
         console.error('Mermaid rendering error:', error);
         return null;
+    }} finally {{
+        clearTimeout(timer);
+    }}
+}};
+
+window.renderMany = async function(diagrams, timeout) {{
+    const results = [];
+    for (const [id, code] of diagrams) {{
+        const result = await window.render(id, code, timeout);
+        results.push(result);
+        // A render which timed out still holds the render queue, so the rest has to wait for a fresh page
+        if (result?.timedOut) {{
+            break;
+        }}
     }}
+    return results;
 }};"
         )
     }
 }
 
+/// Backend used to render diagrams
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase", deserialize = "kebab-case"))]
+pub enum Backend {
+    /// Render in a headless Chrome/Chromium (default)
+    #[default]
+    Chrome,
+    /// Render via a local mermaid-cli (`mmdc`) executable, started once per diagram
+    Mmdc,
+    /// Render via a Kroki-compatible HTTP service
+    Kroki,
+}
+
+/// How rendered diagrams are embedded into the chapters
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase", deserialize = "kebab-case"))]
+pub enum Output {
+    /// Inline the SVG into the chapter (default)
+    #[default]
+    Inline,
+    /// Write the SVG to a content-hashed file, which the chapter references with an `<img>`
+    File,
+    /// Rasterize the SVG to a content-hashed PNG file, which the chapter references with an
+    /// `<img>`
+    Png,
+    /// Like `png`, but wrap the `<img>` in a `<picture>` which prefers the SVG, written to a
+    /// content-hashed file as well, in browsers supporting it
+    Picture,
+    /// Print the SVG to a content-hashed PDF file, which the chapter references with a Markdown
+    /// image, for print pipelines which prefer vector figures
+    Pdf,
+    /// Leave the mermaid code block untouched, for renderers which handle it themselves
+    Passthrough,
+    /// Replace the mermaid code block with a plain code block showing the diagram source
+    Code,
+}
+
 /// How to handle rendering errors
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all(serialize = "camelCase", deserialize = "kebab-case"))]
@@ -157,18 +638,124 @@ mod tests {
         insta::assert_snapshot!(script, @r#"
         mermaid.initialize({"securityLevel":"strict","startOnLoad":false});
 
-        window.render = async function(id, code) {
+        window.render = async function(id, code, timeout) {
+            // Only set up for reproducible builds
+            window.seedRandom?.(id);
+            let timer;
+            const timedOut = new Promise((resolve) => {
+                timer = setTimeout(() => resolve({ timedOut: true }), timeout);
+            });
             try {
-                const { svg } = await mermaid.render(id, code);
-                return svg;
+                const result = await Promise.race([mermaid.render(id, code), timedOut]);
+                return result.timedOut ? result : result.svg;
             } catch (error) {
                 console.error('Mermaid rendering error:', error);
                 return null;
+            } finally {
+                clearTimeout(timer);
             }
         };
+
+        window.renderMany = async function(diagrams, timeout) {
+            const results = [];
+            for (const [id, code] of diagrams) {
+                const result = await window.render(id, code, timeout);
+                results.push(result);
+                // A render which timed out still holds the render queue, so the rest has to wait for a fresh page
+                if (result?.timedOut) {
+                    break;
+                }
+            }
+            return results;
+        };
         "#);
     }
 
+    #[test]
+    fn test_build_seed_directive() {
+        let mut config = Config::default();
+        // Builds are only reproducible if asked for
+        assert_eq!(config.build_seed_directive(42), None);
+        assert!(!config.build_prelude_script().contains("seedRandom"));
+
+        config.reproducible = true;
+        assert_eq!(
+            config.build_seed_directive(42).as_deref(),
+            Some(
+                r#"%%{init: {"deterministicIDSeed":"42","deterministicIds":true,"handDrawnSeed":42}}%%"#
+            )
+        );
+
+        // Explicitly configured seeds take precedence
+        config
+            .mermaid
+            .additional
+            .insert("hand-drawn-seed".to_string(), 0.into());
+        config
+            .mermaid
+            .additional
+            .insert("deterministic-ID-seed".to_string(), "0".into());
+        assert_eq!(
+            config.build_seed_directive(42).as_deref(),
+            Some(r#"%%{init: {"deterministicIds":true}}%%"#)
+        );
+
+        config.reproducible = false;
+        assert_eq!(config.build_seed_directive(42), None);
+        assert!(!config.build_prelude_script().contains("seedRandom"));
+    }
+
+    #[test]
+    fn test_render_date() {
+        let config = Config {
+            render_date: Some(UNIX_EPOCH + Duration::from_hours(473_352)),
+            ..Config::default()
+        };
+        assert!(
+            config
+                .build_prelude_script()
+                .contains("const now = 1704067200000;")
+        );
+
+        assert_eq!(
+            parse_source_date_epoch("1704067200\n").unwrap(),
+            UNIX_EPOCH + Duration::from_hours(473_352)
+        );
+        assert_eq!(
+            parse_source_date_epoch("yesterday")
+                .unwrap_err()
+                .to_string(),
+            r#""yesterday" is not a number of seconds: invalid digit found in string"#
+        );
+    }
+
+    #[test]
+    fn test_output_for() {
+        let mut config = Config {
+            output: Output::File,
+            ..Config::default()
+        };
+        assert_eq!(config.output_for("html"), Output::File);
+        assert_eq!(config.output_for("markdown"), Output::Passthrough);
+        assert!(!config.rasterizes());
+
+        config.renderers = BTreeMap::from([
+            ("html".to_string(), Output::Inline),
+            ("epub".to_string(), Output::Png),
+        ]);
+        assert_eq!(config.output_for("html"), Output::Inline);
+        assert_eq!(config.output_for("epub"), Output::Png);
+        assert!(config.rasterizes());
+        config.renderers.clear();
+        config.output = Output::Picture;
+        assert!(config.rasterizes());
+
+        assert!(config.xhtml_for("epub"));
+        assert!(!config.xhtml_for("html"));
+        config.xhtml = Some(true);
+        assert!(config.xhtml_for("html"));
+    }
+
     #[test]
     fn test_build_init_script_with_additional_options() {
         let mut config = Config::default();
@@ -180,14 +767,35 @@ mod tests {
         insta::assert_snapshot!(script, @r#"
         mermaid.initialize({"securityLevel":"strict","startOnLoad":false,"look":"hand-drawn"});
 
-        window.render = async function(id, code) {
+        window.render = async function(id, code, timeout) {
+            // Only set up for reproducible builds
+            window.seedRandom?.(id);
+            let timer;
+            const timedOut = new Promise((resolve) => {
+                timer = setTimeout(() => resolve({ timedOut: true }), timeout);
+            });
             try {
-                const { svg } = await mermaid.render(id, code);
-                return svg;
+                const result = await Promise.race([mermaid.render(id, code), timedOut]);
+                return result.timedOut ? result : result.svg;
             } catch (error) {
                 console.error('Mermaid rendering error:', error);
                 return null;
+            } finally {
+                clearTimeout(timer);
+            }
+        };
+
+        window.renderMany = async function(diagrams, timeout) {
+            const results = [];
+            for (const [id, code] of diagrams) {
+                const result = await window.render(id, code, timeout);
+                results.push(result);
+                // A render which timed out still holds the render queue, so the rest has to wait for a fresh page
+                if (result?.timedOut) {
+                    break;
+                }
             }
+            return results;
         };
         "#);
     }
@@ -200,15 +808,36 @@ mod tests {
         insta::assert_snapshot!(script, @r#"
         mermaid.initialize({"securityLevel":"antiscript","startOnLoad":false});
 
-        window.render = async function(id, code) {
+        window.render = async function(id, code, timeout) {
+            // Only set up for reproducible builds
+            window.seedRandom?.(id);
+            let timer;
+            const timedOut = new Promise((resolve) => {
+                timer = setTimeout(() => resolve({ timedOut: true }), timeout);
+            });
             try {
-                const { svg } = await mermaid.render(id, code);
-                return svg;
+                const result = await Promise.race([mermaid.render(id, code), timedOut]);
+                return result.timedOut ? result : result.svg;
             } catch (error) {
                 console.error('Mermaid rendering error:', error);
                 return null;
+            } finally {
+                clearTimeout(timer);
             }
         };
+
+        window.renderMany = async function(diagrams, timeout) {
+            const results = [];
+            for (const [id, code] of diagrams) {
+                const result = await window.render(id, code, timeout);
+                results.push(result);
+                // A render which timed out still holds the render queue, so the rest has to wait for a fresh page
+                if (result?.timedOut) {
+                    break;
+                }
+            }
+            return results;
+        };
         "#);
     }
 
@@ -229,11 +858,41 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::too_many_lines)] // Covers every option
     fn test_config_deserialization_from_toml() {
         let toml_str = r#"
             timeout = "60s"
+            launch-timeout = "2m"
+            render-timeout = "10s"
+            idle-timeout = "5m"
             on-error = "comment"
+            on-timeout = "fail"
             chrome-path = "/usr/bin/chromium"
+            chrome-args = ["--disable-dev-shm-usage", "--js-flags=--max-old-space-size=4096"]
+            sandbox = false
+            browser-ws-url = "ws://chrome:9222/devtools/browser/0d1f"
+            remote-debugging-port = 9222
+            render-retries = 0
+            recycle-after = { renders = 500, heap-mb = 256 }
+            backend = "mmdc"
+            mmdc-path = "/opt/mermaid-cli/mmdc"
+            server-url = "http://localhost:8000"
+            server-timeout = "5s"
+            server-retries = 5
+            reproducible = true
+            render-date = "2024-01-01T00:00:00Z"
+            timezone = "Europe/Berlin"
+            locale = "de-DE"
+            allow-network = ["https://cdn.example/icons/", "http://localhost:8080/"]
+            output = "file"
+            renderers = { markdown = "code", epub = "png", typst = "passthrough", html = "picture" }
+            inline-threshold = 4096
+            device-scale-factor = 2.0
+            xhtml = true
+            cache = false
+            cache-dir = "target/mermaid"
+            jobs = 4
+            daemon-socket = "/run/mermaid.sock"
             security-level = "loose"
             theme = "dark"
             look = "handDrawn"
@@ -242,13 +901,100 @@ mod tests {
         let config = toml::from_str(toml_str).expect("Failed to deserialize config");
         let Config {
             timeout,
+            launch_timeout,
+            render_timeout,
+            idle_timeout,
             on_error,
+            on_timeout,
             chrome_path,
+            chrome_args,
+            sandbox,
+            browser_ws_url,
+            remote_debugging_port,
+            render_retries,
+            recycle_after,
+            backend,
+            mmdc_path,
+            server_url,
+            server_timeout,
+            server_retries,
+            reproducible,
+            render_date,
+            timezone,
+            locale,
+            allow_network,
+            output,
+            renderers,
+            inline_threshold,
+            device_scale_factor,
+            xhtml,
+            cache,
+            cache_dir,
+            jobs,
+            daemon_socket,
             mermaid,
         } = config;
-        assert_eq!(timeout, Duration::from_secs(60));
+        assert_eq!(timeout, Duration::from_mins(1));
+        assert_eq!(launch_timeout, Some(Duration::from_mins(2)));
+        assert_eq!(render_timeout, Some(Duration::from_secs(10)));
+        assert_eq!(idle_timeout, Some(Duration::from_mins(5)));
         assert_eq!(on_error, ErrorHandling::Comment);
+        assert_eq!(on_timeout, Some(ErrorHandling::Fail));
         assert_eq!(chrome_path, Some(PathBuf::from("/usr/bin/chromium")));
+        assert_eq!(
+            chrome_args,
+            [
+                "--disable-dev-shm-usage",
+                "--js-flags=--max-old-space-size=4096"
+            ]
+        );
+        assert!(!sandbox);
+        assert_eq!(
+            browser_ws_url.as_deref(),
+            Some("ws://chrome:9222/devtools/browser/0d1f")
+        );
+        assert_eq!(remote_debugging_port, Some(9222));
+        assert_eq!(render_retries, 0);
+        assert_eq!(
+            recycle_after,
+            RecycleAfter {
+                renders: NonZeroUsize::new(500),
+                heap_mb: Some(256),
+            }
+        );
+        assert_eq!(backend, Backend::Mmdc);
+        assert_eq!(mmdc_path, Some(PathBuf::from("/opt/mermaid-cli/mmdc")));
+        assert_eq!(server_url.as_deref(), Some("http://localhost:8000"));
+        assert_eq!(server_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(server_retries, 5);
+        assert!(reproducible);
+        assert_eq!(
+            render_date,
+            Some(UNIX_EPOCH + Duration::from_hours(473_352))
+        );
+        assert_eq!(timezone.as_deref(), Some("Europe/Berlin"));
+        assert_eq!(locale.as_deref(), Some("de-DE"));
+        assert_eq!(
+            allow_network,
+            ["https://cdn.example/icons/", "http://localhost:8080/"]
+        );
+        assert_eq!(output, Output::File);
+        assert_eq!(
+            renderers,
+            BTreeMap::from([
+                ("epub".to_string(), Output::Png),
+                ("html".to_string(), Output::Picture),
+                ("markdown".to_string(), Output::Code),
+                ("typst".to_string(), Output::Passthrough),
+            ])
+        );
+        assert_eq!(inline_threshold, 4096);
+        assert!((device_scale_factor - 2.0).abs() < f64::EPSILON);
+        assert_eq!(xhtml, Some(true));
+        assert!(!cache);
+        assert_eq!(cache_dir, Some(PathBuf::from("target/mermaid")));
+        assert_eq!(jobs, NonZeroUsize::new(4));
+        assert_eq!(daemon_socket, Some(PathBuf::from("/run/mermaid.sock")));
         insta::assert_json_snapshot!(mermaid, @r#"
         {
           "securityLevel": "loose",
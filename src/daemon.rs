@@ -0,0 +1,291 @@
+//! A long-running render daemon, which keeps a warm browser around between builds
+//!
+//! `mdbook serve` starts a new preprocessor process for every rebuild.
+//! Instead of launching a browser each time, the preprocessor hands its render jobs to the daemon
+//! via a Unix socket, if one is listening.
+//!
+//! The protocol is newline-delimited JSON: each line sent by the client is a [`Request`] holding
+//! the diagrams of one chapter, which the daemon renders as one batch and answers with exactly one
+//! [`Response`] line.
+
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::renderer::{self, RenderTimeout};
+
+/// A batch of render jobs sent to the daemon
+#[derive(Debug, Serialize, Deserialize)]
+struct Request {
+    /// The page setup of the client, which has to match the one of the daemon
+    page_setup: String,
+    /// The diagram sources
+    sources: Vec<String>,
+}
+
+/// The answer to a [`Request`]
+#[derive(Debug, Serialize, Deserialize)]
+enum Response {
+    /// The outcome of each diagram, in the order of the request
+    Rendered(Vec<Outcome>),
+    /// The daemon was started with a different configuration than the client
+    ConfigMismatch,
+}
+
+/// The outcome of rendering one diagram of a [`Request`]
+#[derive(Debug, Serialize, Deserialize)]
+enum Outcome {
+    /// The rendered SVG
+    Svg(String),
+    /// Rendering the diagram failed, for example due to a syntax error
+    Failed(String),
+    /// Rendering the diagram exceeded the render timeout of the daemon
+    TimedOut(Duration),
+}
+
+/// Runs the daemon until the process is terminated
+///
+/// # Arguments
+/// * `socket` - Path of the Unix socket to listen on
+/// * `config` - Configuration for the renderer
+pub fn serve(socket: &Path, config: &Config) -> Result<()> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            bail!("A daemon is already listening on {}", socket.display());
+        }
+        // Left behind by a daemon which did not shut down cleanly
+        std::fs::remove_file(socket)
+            .with_context(|| format!("Failed to remove stale socket {}", socket.display()))?;
+    }
+
+    let renderer = renderer::Mermaid::try_init_with_config(config)
+        .context("Failed to initialize SSR renderer. Chrome/Chromium must be installed.")?;
+    let page_setup = config.build_page_setup();
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    log::info!("Listening for render jobs on {}", socket.display());
+
+    thread::scope(|scope| {
+        // Between builds no diagrams are rendered, which would otherwise let the browser idle out
+        scope.spawn(|| {
+            loop {
+                thread::sleep(config.idle_timeout() / 2);
+                if let Err(e) = renderer.browser().get_version() {
+                    log::warn!("Failed to keep the browser alive: {e}");
+                }
+            }
+        });
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("Failed to accept connection: {e}");
+                    continue;
+                }
+            };
+            let (renderer, page_setup) = (&renderer, &page_setup);
+            scope.spawn(move || {
+                if let Err(e) = handle_connection(&stream, renderer, page_setup) {
+                    log::warn!("Connection closed: {e}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn handle_connection(
+    stream: &UnixStream,
+    renderer: &renderer::Mermaid,
+    page_setup: &str,
+) -> Result<()> {
+    let mut writer = stream;
+    for line in BufReader::new(stream).lines() {
+        let request: Request = serde_json::from_str(&line?)?;
+        let response = if request.page_setup == page_setup {
+            let sources: Vec<&str> = request.sources.iter().map(String::as_str).collect();
+            Response::Rendered(
+                renderer
+                    .render_many(&sources)
+                    .into_iter()
+                    .map(|result| match result {
+                        Ok(svg) => Outcome::Svg(svg),
+                        Err(e) => match e.downcast_ref::<RenderTimeout>() {
+                            Some(RenderTimeout(timeout)) => Outcome::TimedOut(*timeout),
+                            None => Outcome::Failed(e.to_string()),
+                        },
+                    })
+                    .collect(),
+            )
+        } else {
+            Response::ConfigMismatch
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Client for a daemon started via [`serve`]
+#[derive(Debug)]
+pub struct Client {
+    socket: PathBuf,
+    page_setup: String,
+    /// Set once the daemon turned out to be unusable, to avoid asking it again for every chapter
+    unavailable: AtomicBool,
+}
+
+impl Client {
+    /// Creates a client for the daemon listening on `socket`
+    ///
+    /// No connection is made until the first diagram is rendered.
+    #[must_use]
+    pub fn new(socket: impl Into<PathBuf>, config: &Config) -> Self {
+        Self {
+            socket: socket.into(),
+            page_setup: config.build_page_setup(),
+            unavailable: AtomicBool::new(false),
+        }
+    }
+
+    /// Renders diagrams via the daemon, as one batch
+    ///
+    /// Returns one result per source, in the same order, or `None` if no daemon is available, in
+    /// which case the caller should render the diagrams itself.
+    pub fn render_many(&self, sources: &[&str]) -> Option<Vec<Result<String>>> {
+        if self.unavailable.load(Ordering::Relaxed) {
+            return None;
+        }
+        match self.request(sources) {
+            Ok(Response::Rendered(outcomes)) if outcomes.len() == sources.len() => Some(
+                outcomes
+                    .into_iter()
+                    .map(|outcome| match outcome {
+                        Outcome::Svg(svg) => Ok(svg),
+                        Outcome::Failed(e) => Err(anyhow::anyhow!(e)),
+                        Outcome::TimedOut(timeout) => Err(RenderTimeout(timeout).into()),
+                    })
+                    .collect(),
+            ),
+            Ok(Response::Rendered(outcomes)) => {
+                log::warn!(
+                    "The render daemon answered {} of {} diagrams, rendering in-process instead",
+                    outcomes.len(),
+                    sources.len()
+                );
+                self.unavailable.store(true, Ordering::Relaxed);
+                None
+            }
+            Ok(Response::ConfigMismatch) => {
+                log::warn!(
+                    "The render daemon on {} was started with a different configuration, rendering in-process instead",
+                    self.socket.display()
+                );
+                self.unavailable.store(true, Ordering::Relaxed);
+                None
+            }
+            Err(e) => {
+                let not_running = e.downcast_ref::<std::io::Error>().is_some_and(|e| {
+                    matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused)
+                });
+                if not_running {
+                    log::debug!("No render daemon on {}", self.socket.display());
+                } else {
+                    log::warn!("The render daemon failed, rendering in-process instead: {e}");
+                }
+                self.unavailable.store(true, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn request(&self, sources: &[&str]) -> Result<Response> {
+        let mut stream = UnixStream::connect(&self.socket)?;
+        let request = Request {
+            page_setup: self.page_setup.clone(),
+            sources: sources.iter().map(ToString::to_string).collect(),
+        };
+        serde_json::to_writer(&mut stream, &request)?;
+        stream.write_all(b"\n")?;
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_without_daemon_falls_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = Client::new(dir.path().join("daemon.sock"), &Config::default());
+        assert!(client.render_many(&["graph TD\nA-->B"]).is_none());
+        assert!(client.unavailable.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn client_reports_config_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("daemon.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let daemon = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            let request: Request = serde_json::from_str(&line).unwrap();
+            assert_eq!(request.sources, ["graph TD\nA-->B"]);
+            serde_json::to_writer(&stream, &Response::ConfigMismatch).unwrap();
+            (&stream).write_all(b"\n").unwrap();
+        });
+
+        let client = Client::new(&socket, &Config::default());
+        assert!(client.render_many(&["graph TD\nA-->B"]).is_none());
+        daemon.join().unwrap();
+        // The daemon is not asked again
+        assert!(client.render_many(&["graph TD\nA-->B"]).is_none());
+    }
+
+    #[test]
+    fn client_sends_diagrams_as_one_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("daemon.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let daemon = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            let request: Request = serde_json::from_str(&line).unwrap();
+            assert_eq!(request.sources, ["graph TD\nA-->B", "gantt", "grph"]);
+            let response = Response::Rendered(vec![
+                Outcome::Svg("<svg></svg>".to_string()),
+                Outcome::TimedOut(Duration::from_secs(10)),
+                Outcome::Failed("No diagram type detected".to_string()),
+            ]);
+            serde_json::to_writer(&stream, &response).unwrap();
+            (&stream).write_all(b"\n").unwrap();
+        });
+
+        let client = Client::new(&socket, &Config::default());
+        let results = client
+            .render_many(&["graph TD\nA-->B", "gantt", "grph"])
+            .unwrap();
+        daemon.join().unwrap();
+        assert_eq!(results[0].as_ref().unwrap(), "<svg></svg>");
+        assert!(results[1].as_ref().unwrap_err().is::<RenderTimeout>());
+        assert_eq!(
+            results[2].as_ref().unwrap_err().to_string(),
+            "No diagram type detected"
+        );
+    }
+}
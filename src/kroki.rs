@@ -0,0 +1,276 @@
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use ureq::Agent;
+
+use crate::config::Config;
+use crate::renderer::{DiagramRenderer, RenderTimeout};
+
+/// Delay before the first retry, doubled for every further retry
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Renders diagrams via a Kroki-compatible HTTP service
+///
+/// Each diagram is sent as the body of a `POST {server-url}/mermaid/svg` request.
+/// The mermaid options are passed on as an `%%{init: ...}%%` directive in front of the diagram,
+/// after its frontmatter.
+pub struct Kroki {
+    agent: Agent,
+    url: String,
+    retries: u32,
+    timeout: Duration,
+    init_directive: String,
+}
+
+impl Kroki {
+    /// Initializes the backend with a configuration object
+    ///
+    /// Fails if no `server-url` is configured.
+    pub fn try_init_with_config(config: &Config) -> Result<Self> {
+        let Some(ref server_url) = config.server_url else {
+            bail!("The kroki backend requires `server-url` to be set");
+        };
+        let timeout = config.server_timeout.unwrap_or(config.render_timeout());
+        let agent = Agent::config_builder()
+            .timeout_global(Some(timeout))
+            // Error responses carry the reason why the diagram could not be rendered
+            .http_status_as_error(false)
+            .build()
+            .into();
+        Ok(Self {
+            agent,
+            url: format!("{}/mermaid/svg", server_url.trim_end_matches('/')),
+            retries: config.server_retries,
+            timeout,
+            init_directive: format!("%%{{init: {}}}%%", config.build_mermaid_config_json()),
+        })
+    }
+
+    /// Sends a single render request
+    ///
+    /// Returns `Err` for failures which might succeed when retried, and `Ok(Err(..))` for those
+    /// which will not, such as syntax errors in the diagram or timeouts.
+    fn request(&self, body: &str) -> Result<Result<String>> {
+        let response = self
+            .agent
+            .post(&self.url)
+            .header("Content-Type", "text/plain")
+            .header("Accept", "image/svg+xml")
+            .send(body);
+        let mut response = match response {
+            Ok(response) => response,
+            Err(e) => return self.transport_error(e),
+        };
+        let status = response.status();
+        let text = match response.body_mut().read_to_string() {
+            Ok(text) => text,
+            Err(e) => return self.transport_error(e),
+        };
+        if status.is_server_error() {
+            bail!("{} responded with {status}: {}", self.url, text.trim());
+        }
+        if !status.is_success() {
+            return Ok(Err(anyhow::anyhow!(
+                "Failed to compile Mermaid diagram: {}",
+                text.trim()
+            )));
+        }
+        if text.is_empty() {
+            return Ok(Err(anyhow::anyhow!(
+                "Failed to compile Mermaid diagram: empty result"
+            )));
+        }
+        Ok(Ok(text))
+    }
+
+    /// Classifies a failed request like [`Self::request`]
+    ///
+    /// Timeouts are not retried, like with the other backends.
+    fn transport_error(&self, e: ureq::Error) -> Result<Result<String>> {
+        match e {
+            ureq::Error::Timeout(_) => Ok(Err(RenderTimeout(self.timeout).into())),
+            e => Err(e.into()),
+        }
+    }
+}
+
+impl DiagramRenderer for Kroki {
+    fn render(&self, input: &str) -> Result<String> {
+        let body = crate::add_directive(input, &self.init_directive);
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            match self.request(&body) {
+                Ok(rendered) => return rendered,
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "Rendering via {} failed, retrying ({attempt}/{}): {e}",
+                        self.url,
+                        self.retries
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("Failed to render via {} after {attempt} retries", self.url)
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// Answers each incoming request with the next of `responses`, returning the request bodies
+    fn serve(responses: Vec<&'static str>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut bodies = vec![];
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(length) = line.to_ascii_lowercase().strip_prefix("content-length:")
+                    {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(String::from_utf8(body).unwrap());
+                (&stream).write_all(response.as_bytes()).unwrap();
+            }
+            bodies
+        });
+        (url, server)
+    }
+
+    fn response(status: &str, body: &str) -> &'static str {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .leak()
+    }
+
+    #[test]
+    fn requires_server_url() {
+        let err = Kroki::try_init_with_config(&Config::default())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "The kroki backend requires `server-url` to be set"
+        );
+    }
+
+    #[test]
+    fn renders_and_retries_server_errors() {
+        let (url, server) = serve(vec![
+            response("503 Service Unavailable", "busy"),
+            response("200 OK", "<svg></svg>"),
+        ]);
+        let config = Config {
+            server_url: Some(url),
+            server_retries: 1,
+            ..Config::default()
+        };
+        let kroki = Kroki::try_init_with_config(&config).unwrap();
+
+        assert_eq!(kroki.render("graph TD\nA-->B").unwrap(), "<svg></svg>");
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(
+            bodies[0],
+            "%%{init: {\"securityLevel\":\"strict\",\"startOnLoad\":false}}%%\ngraph TD\nA-->B"
+        );
+    }
+
+    #[test]
+    fn keeps_frontmatter_first() {
+        let (url, server) = serve(vec![response("200 OK", "<svg></svg>")]);
+        let config = Config {
+            server_url: Some(url),
+            ..Config::default()
+        };
+        let kroki = Kroki::try_init_with_config(&config).unwrap();
+
+        kroki
+            .render("---\ntitle: Flow\n---\ngraph TD\nA-->B")
+            .unwrap();
+        assert_eq!(
+            server.join().unwrap()[0],
+            "---\ntitle: Flow\n---\n%%{init: {\"securityLevel\":\"strict\",\"startOnLoad\":false}}%%\ngraph TD\nA-->B"
+        );
+    }
+
+    #[test]
+    fn does_not_retry_timeouts() {
+        // Accepts connections, but never responds
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::clone(&connections);
+        thread::spawn(move || {
+            let mut streams = vec![];
+            for stream in listener.incoming() {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                streams.push(stream);
+            }
+        });
+        let config = Config {
+            server_url: Some(url),
+            server_timeout: Some(Duration::from_millis(200)),
+            server_retries: 2,
+            ..Config::default()
+        };
+        let kroki = Kroki::try_init_with_config(&config).unwrap();
+
+        let err = kroki.render("graph TD\nA-->B").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RenderTimeout>(),
+            Some(&RenderTimeout(Duration::from_millis(200)))
+        );
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn does_not_retry_syntax_errors() {
+        let (url, server) = serve(vec![response(
+            "400 Bad Request",
+            "Error 400: No diagram type detected",
+        )]);
+        let config = Config {
+            server_url: Some(url),
+            server_retries: 3,
+            ..Config::default()
+        };
+        let kroki = Kroki::try_init_with_config(&config).unwrap();
+
+        let err = kroki.render("grph TD\nA-->B").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Failed to compile Mermaid diagram: Error 400: No diagram type detected"
+        );
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+}
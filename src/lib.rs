@@ -2,26 +2,280 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod cache;
 pub mod config;
+#[cfg(unix)]
+pub mod daemon;
+pub mod kroki;
+pub mod mmdc;
+pub mod output;
 pub mod renderer;
+pub mod svg;
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use anyhow::Context;
-use config::{Config, ErrorHandling};
-use mdbook_preprocessor::book::{Book, BookItem};
+use cache::Cache;
+use config::{Backend, Config, ErrorHandling, Output};
+use mdbook_preprocessor::book::{Book, BookItem, Chapter};
 use mdbook_preprocessor::errors::Result;
 use mdbook_preprocessor::{Preprocessor, PreprocessorContext};
 use pulldown_cmark::{CodeBlockKind::Fenced, Event, Options, Parser, Tag, TagEnd};
+use renderer::{DiagramRenderer, RenderTimeout};
+
+/// Renderers which empty their output directory after the preprocessors ran
+const CLEANING_RENDERERS: &[&str] = &["html", "markdown"];
 
 pub struct Mermaid {
-    renderer: renderer::Mermaid,
+    /// Launched on the first diagram which is not already cached, unless one was provided
+    ///
+    /// A failed launch is kept as well, so that it is not retried for every batch.
+    renderer: Mutex<Option<std::result::Result<Arc<dyn DiagramRenderer>, String>>>,
+    cache: Option<Cache>,
+    #[cfg(unix)]
+    daemon: Option<daemon::Client>,
     config: Config,
 }
 
 impl Mermaid {
-    pub fn new(config: Config) -> Result<Self> {
-        let renderer = renderer::Mermaid::try_init_with_config(&config)
-            .context("Failed to initialize SSR renderer. Chrome/Chromium must be installed.")?;
-        Ok(Self { renderer, config })
+    /// Creates the preprocessor
+    ///
+    /// The browser is only launched once the first diagram needs to be rendered.
+    #[must_use]
+    pub fn new(config: Config) -> Self {
+        // The backends produce slightly different SVGs, so they must not share cache entries
+        let fingerprint = format!("{:?}\n{}", config.backend, config.build_page_setup());
+        let cache = config.cache_dir().map(|dir| Cache::open(dir, &fingerprint));
+        Self {
+            renderer: Mutex::default(),
+            cache,
+            #[cfg(unix)]
+            daemon: (config.backend == Backend::Chrome)
+                .then(|| daemon::Client::new(config.daemon_socket(), &config)),
+            config,
+        }
+    }
+
+    /// Creates the preprocessor with a custom rendering backend
+    ///
+    /// The render daemon is not used in this case.
+    #[must_use]
+    pub fn with_renderer(config: Config, renderer: impl DiagramRenderer + 'static) -> Self {
+        Self {
+            renderer: Mutex::new(Some(Ok(Arc::new(renderer)))),
+            #[cfg(unix)]
+            daemon: None,
+            ..Self::new(config)
+        }
+    }
+
+    fn renderer(&self) -> Result<Arc<dyn DiagramRenderer>> {
+        // Holding the lock while launching makes sure concurrent renders share one browser
+        let mut renderer = self
+            .renderer
+            .lock()
+            .expect("renderer lock is never poisoned");
+        let launched = renderer
+            .get_or_insert_with(|| launch_renderer(&self.config).map_err(|e| format!("{e:#}")));
+        match launched {
+            Ok(renderer) => Ok(Arc::clone(renderer)),
+            Err(e) => Err(anyhow::anyhow!("{e}")),
+        }
+    }
+
+    /// Renders diagrams, consulting the cache first
+    ///
+    /// The remaining diagrams are rendered as one batch, by the render daemon if one is running.
+    fn render_many(&self, inputs: &[&str]) -> Vec<Result<String>> {
+        let mut results: Vec<Option<Result<String>>> = inputs
+            .iter()
+            .map(|input| {
+                let svg = self.cache.as_ref().and_then(|cache| cache.get(input))?;
+                log::debug!("Using cached rendering of mermaid diagram");
+                Some(Ok(svg))
+            })
+            .collect();
+
+        let pending: Vec<usize> = (0..inputs.len())
+            .filter(|&index| results[index].is_none())
+            .collect();
+        if !pending.is_empty() {
+            let sources: Vec<&str> = pending.iter().map(|&index| inputs[index]).collect();
+            #[cfg(unix)]
+            let from_daemon = self
+                .daemon
+                .as_ref()
+                .and_then(|daemon| daemon.render_many(&sources));
+            #[cfg(not(unix))]
+            let from_daemon = None;
+            let svgs = from_daemon.unwrap_or_else(|| match self.renderer() {
+                Ok(renderer) => {
+                    let svgs = renderer.render_many(&sources);
+                    if svgs.len() == sources.len() {
+                        svgs
+                    } else {
+                        let answered = svgs.len();
+                        sources
+                            .iter()
+                            .map(|_| {
+                                Err(anyhow::anyhow!(
+                                    "The renderer answered {answered} of {} diagrams",
+                                    sources.len()
+                                ))
+                            })
+                            .collect()
+                    }
+                }
+                Err(e) => sources
+                    .iter()
+                    .map(|_| Err(anyhow::anyhow!("{e:#}")))
+                    .collect(),
+            });
+            for (index, svg) in pending.into_iter().zip(svgs) {
+                // Cache hits are marked as used by `get` already, and need not be written again
+                if let (Some(cache), Ok(svg)) = (&self.cache, &svg)
+                    && let Err(e) = cache.put(inputs[index], svg)
+                {
+                    log::warn!("Failed to cache rendered mermaid diagram: {e}");
+                }
+                results[index] = Some(svg);
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|svg| svg.expect("every diagram is rendered"))
+            .collect()
+    }
+
+    /// Renders the diagrams of `chapters`, given by their ID and mermaid blocks
+    ///
+    /// Returns the index of the rendering of each diagram, per chapter, and the renderings.
+    fn render_chapters(
+        &self,
+        chapters: &[(String, Vec<MermaidBlock>)],
+    ) -> (Vec<Vec<usize>>, Vec<Result<String>>) {
+        // All diagrams of a run share the same configuration, so identical sources render
+        // identically. Each unique diagram is rendered in the batch of the chapter it first
+        // occurs in, seeded by the position of that occurrence for reproducible builds.
+        let mut unique: HashMap<&str, usize> = HashMap::new();
+        let mut batches: Vec<Vec<String>> = vec![];
+        let mut occurrences: Vec<Vec<usize>> = vec![];
+        for (chapter_id, blocks) in chapters {
+            let mut batch = vec![];
+            occurrences.push(
+                blocks
+                    .iter()
+                    .enumerate()
+                    .map(|(block, MermaidBlock { code, .. })| {
+                        let next = unique.len();
+                        *unique.entry(code.as_str()).or_insert_with(|| {
+                            let seed = fxhash::hash32(&(chapter_id.as_str(), block));
+                            batch.push(match self.config.build_seed_directive(seed) {
+                                Some(directive) => add_directive(code, &directive),
+                                None => code.clone(),
+                            });
+                            next
+                        })
+                    })
+                    .collect(),
+            );
+            batches.push(batch);
+        }
+        let diagrams: usize = occurrences.iter().map(Vec::len).sum();
+        if diagrams == 0 {
+            log::debug!("No mermaid diagrams found, not launching a browser");
+        } else if diagrams > unique.len() {
+            log::info!(
+                "Deduplication saved {} of {diagrams} renders",
+                diagrams - unique.len()
+            );
+        }
+
+        let jobs = self.config.jobs();
+        log::debug!("Rendering {} diagrams using {jobs} jobs", unique.len());
+        // Each chapter is rendered as one batch. Once a diagram fails the build, the chapters
+        // which were not started yet are skipped, as their diagrams would never be embedded.
+        let failed = AtomicBool::new(false);
+        let rendered: Vec<Result<String>> = render_concurrently(&batches, jobs, |batch| {
+            if batch.is_empty() {
+                vec![]
+            } else if failed.load(Ordering::Relaxed) {
+                batch
+                    .iter()
+                    .map(|_| {
+                        Err(anyhow::anyhow!(
+                            "Not rendered, as an earlier diagram failed"
+                        ))
+                    })
+                    .collect()
+            } else {
+                let rendered =
+                    self.render_many(&batch.iter().map(String::as_str).collect::<Vec<_>>());
+                if rendered.iter().any(|result| {
+                    result
+                        .as_ref()
+                        .is_err_and(|e| error_handling(e, &self.config).0 == ErrorHandling::Fail)
+                }) {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                rendered
+            }
+        })
+        .into_iter()
+        .flatten()
+        .collect();
+        (occurrences, rendered)
+    }
+
+    /// Embeds a rendered `svg` into the chapter at `chapter_path`, according to `output`
+    ///
+    /// Inlined SVGs have their IDs moved into `namespace`. Files do not share a page, so their
+    /// IDs cannot collide.
+    fn embed(
+        &self,
+        svg: &str,
+        output: Output,
+        files: &mut output::DiagramFiles,
+        chapter_path: Option<&Path>,
+        namespace: impl FnOnce() -> String,
+    ) -> Result<String> {
+        match output {
+            Output::Pdf => files.write_pdf(svg, chapter_path, || self.renderer()?.print_pdf(svg)),
+            Output::Png | Output::Picture if svg.len() >= self.config.inline_threshold => files
+                .write_png(
+                    svg,
+                    self.config.device_scale_factor,
+                    output == Output::Picture,
+                    chapter_path,
+                    || self.renderer()?.rasterize(svg),
+                ),
+            Output::File if svg.len() >= self.config.inline_threshold => {
+                files.write_svg(svg, chapter_path)
+            }
+            _ => Ok(svg::namespace_ids(svg, &namespace())),
+        }
+    }
+
+    /// Removes the diagrams which the book no longer uses from the cache and from `files`
+    fn evict_unused(&self, files: &output::DiagramFiles) {
+        if let Some(cache) = &self.cache {
+            match cache.evict_unused() {
+                Ok(0) => {}
+                Ok(evicted) => log::debug!("Evicted {evicted} unused diagrams from the cache"),
+                Err(e) => log::warn!("Failed to evict unused diagrams from the cache: {e}"),
+            }
+        }
+        match files.evict_unused() {
+            Ok(0) => {}
+            Ok(evicted) => log::debug!("Removed {evicted} unused diagram files"),
+            Err(e) => log::warn!("Failed to remove unused diagram files: {e}"),
+        }
     }
 }
 
@@ -30,34 +284,127 @@ impl Preprocessor for Mermaid {
         "mermaid-ssr"
     }
 
-    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
+        let output = self.config.output_for(&ctx.renderer);
+        check_output(&ctx.renderer, output)?;
+        if matches!(output, Output::Passthrough | Output::Code) {
+            log::debug!(
+                "Not rendering mermaid diagrams for the {} renderer",
+                ctx.renderer
+            );
+            if output == Output::Code {
+                show_sources(&mut book);
+            }
+            return Ok(book);
+        }
         log::info!("Rendering mermaid diagrams with SSR");
 
+        // Collect all diagrams of the book first, so that each one is rendered only once and the
+        // chapters can be rendered concurrently
+        let mut chapters = vec![];
+        book.for_each_mut(|item: &mut BookItem| {
+            if let BookItem::Chapter(ref chapter) = *item {
+                chapters.push((chapter_id(chapter), find_mermaid_blocks(&chapter.content)));
+            }
+        });
+
+        let (occurrences, rendered) = self.render_chapters(&chapters);
+
+        // Splice the rendered diagrams back in, in document order
+        let xhtml = self.config.xhtml_for(&ctx.renderer);
+        // Inlined diagrams need no files, but those of earlier builds with another output are
+        // still removed
+        let mut files = match output {
+            // Print backends read the PDFs from disk, next to the rest of their output
+            Output::Pdf => output::DiagramFiles::in_build_dir(&build_dir(ctx)?),
+            _ => output::DiagramFiles::new(&ctx.root.join(&ctx.config.book.src), &ctx.renderer),
+        };
+        let mut chapters = chapters.iter().zip(occurrences);
         let mut res = None;
         book.for_each_mut(|item: &mut BookItem| {
             if let Some(Err(_)) = res {
                 return;
             }
 
-            if let BookItem::Chapter(ref mut chapter) = *item {
+            if let BookItem::Chapter(ref mut chapter) = *item
+                && let Some(((chapter_id, blocks), occurrences)) = chapters.next()
+            {
+                let chapter_path = chapter.path.clone();
+                let svgs = occurrences.into_iter().enumerate().map(|(block, index)| {
+                    let svg = copy_rendered(&rendered[index])?;
+                    let svg = if xhtml { svg::to_xml(&svg) } else { svg };
+                    self.embed(&svg, output, &mut files, chapter_path.as_deref(), || {
+                        format!(
+                            "mermaid-diagram-{}",
+                            fxhash::hash64(&(chapter_id.as_str(), block))
+                        )
+                    })
+                });
                 res = Some(
-                    add_mermaid(&chapter.content, &self.renderer, &self.config).map(|md| {
+                    splice_mermaid(&chapter.content, blocks, svgs, &self.config).map(|md| {
                         chapter.content = md;
                     }),
                 );
             }
         });
 
-        res.unwrap_or(Ok(())).map(|()| book)
+        res.unwrap_or(Ok(()))?;
+
+        // Only evict after a successful run, as a failed one has not seen every diagram
+        self.evict_unused(&files);
+        Ok(book)
     }
 
     fn supports_renderer(&self, renderer: &str) -> Result<bool> {
-        Ok(renderer == "html")
+        let output = self.config.output_for(renderer);
+        check_output(renderer, output)?;
+        Ok(output != Output::Passthrough)
     }
 }
 
-fn add_mermaid(content: &str, renderer: &renderer::Mermaid, config: &Config) -> Result<String> {
-    let mut mermaid_content = String::new();
+/// Rejects an `output` which cannot work with `renderer`
+fn check_output(renderer: &str, output: Output) -> Result<()> {
+    if output == Output::Pdf && CLEANING_RENDERERS.contains(&renderer) {
+        anyhow::bail!(
+            "The {renderer} renderer empties its output directory, which would remove the PDFs of \
+             `output = \"pdf\"`"
+        );
+    }
+    Ok(())
+}
+
+/// Launches the rendering backend selected by `config.backend`
+fn launch_renderer(config: &Config) -> Result<Arc<dyn DiagramRenderer>> {
+    Ok(match config.backend {
+        Backend::Chrome => {
+            let renderer = renderer::Mermaid::try_init_with_config(config)
+                .context("Failed to initialize SSR renderer. Chrome/Chromium must be installed.")?;
+            Arc::new(renderer)
+        }
+        Backend::Mmdc => {
+            let renderer = mmdc::Mmdc::try_init_with_config(config)
+                .context("Failed to initialize mmdc renderer. mermaid-cli must be installed.")?;
+            Arc::new(renderer)
+        }
+        Backend::Kroki => {
+            let renderer = kroki::Kroki::try_init_with_config(config)
+                .context("Failed to initialize kroki renderer.")?;
+            Arc::new(renderer)
+        }
+    })
+}
+
+/// A mermaid code block within a chapter
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MermaidBlock {
+    /// Span of the whole fenced code block, including the fences
+    span: Range<usize>,
+    /// The diagram source
+    code: String,
+}
+
+/// Finds all mermaid code blocks in `content`, in document order
+fn find_mermaid_blocks(content: &str) -> Vec<MermaidBlock> {
     let mut in_mermaid_block = false;
 
     let mut opts = Options::empty();
@@ -66,8 +413,7 @@ fn add_mermaid(content: &str, renderer: &renderer::Mermaid, config: &Config) ->
     opts.insert(Options::ENABLE_STRIKETHROUGH);
     opts.insert(Options::ENABLE_TASKLISTS);
 
-    let mut code_span = 0..0;
-    let mut start_new_code_span = true;
+    let mut code_span: Option<Range<usize>> = None;
 
     let mut mermaid_blocks = vec![];
 
@@ -77,7 +423,6 @@ fn add_mermaid(content: &str, renderer: &renderer::Mermaid, config: &Config) ->
         if let Event::Start(Tag::CodeBlock(Fenced(code))) = e {
             if code.as_ref() == "mermaid" {
                 in_mermaid_block = true;
-                mermaid_content.clear();
             }
             continue;
         }
@@ -89,12 +434,10 @@ fn add_mermaid(content: &str, renderer: &renderer::Mermaid, config: &Config) ->
         // We're in the code block. The text is what we want.
         // Code blocks can come in multiple text events.
         if let Event::Text(_) = e {
-            if start_new_code_span {
-                code_span = span;
-                start_new_code_span = false;
-            } else {
-                code_span = code_span.start..span.end;
-            }
+            code_span = Some(match code_span {
+                None => span,
+                Some(code_span) => code_span.start..span.end,
+            });
 
             continue;
         }
@@ -102,32 +445,61 @@ fn add_mermaid(content: &str, renderer: &renderer::Mermaid, config: &Config) ->
         if let Event::End(TagEnd::CodeBlock) = e {
             in_mermaid_block = false;
 
-            let mermaid_content = &content[code_span.clone()];
+            let code = code_span.take().map_or("", |code_span| &content[code_span]);
+            mermaid_blocks.push(MermaidBlock {
+                span,
+                code: code.to_string(),
+            });
+        }
+    }
 
-            // Render to SVG directly using SSR
-            let mermaid_code = match renderer.render(mermaid_content) {
-                Ok(svg) => {
-                    log::info!("Successfully rendered mermaid diagram to SVG");
-                    format!("{svg}\n\n")
-                }
-                Err(e) => {
-                    log::error!(
-                        "Failed to render mermaid diagram: {e}. Content: {mermaid_content}"
-                    );
-
-                    // Handle error based on configuration
-                    match config.on_error {
-                        ErrorHandling::Fail => {
-                            return Err(e);
-                        }
-                        ErrorHandling::Comment => {
-                            let mermaid_code = mermaid_content
-                                .replace("```", "``\\`")
-                                .lines()
-                                .collect::<Vec<_>>()
-                                .join("\n> ");
-                            format!(
-                                r"> [!IMPORTANT]
+    mermaid_blocks
+}
+
+/// Returns how to handle the rendering error `e`, and the setting which decides that
+fn error_handling(e: &anyhow::Error, config: &Config) -> (ErrorHandling, &'static str) {
+    if e.downcast_ref::<RenderTimeout>().is_some() {
+        (config.on_timeout(), "on-timeout")
+    } else {
+        (config.on_error, "on-error")
+    }
+}
+
+/// Replaces `blocks` in `content` with their `rendered` counterparts
+///
+/// `rendered` holds one render result per block, in the same order as `blocks`.
+/// Errors are handled according to `config.on_error`.
+fn splice_mermaid(
+    content: &str,
+    blocks: &[MermaidBlock],
+    rendered: impl IntoIterator<Item = Result<String>>,
+    config: &Config,
+) -> Result<String> {
+    let mut mermaid_blocks = vec![];
+    for (block, result) in blocks.iter().zip(rendered) {
+        let mermaid_content = &block.code;
+        let mermaid_code = match result {
+            Ok(svg) => {
+                log::info!("Successfully rendered mermaid diagram to SVG");
+                format!("{svg}\n\n")
+            }
+            Err(e) => {
+                log::error!("Failed to render mermaid diagram: {e}. Content: {mermaid_content}");
+
+                // Handle error based on configuration
+                let (error_handling, setting) = error_handling(&e, config);
+                match error_handling {
+                    ErrorHandling::Fail => {
+                        return Err(e);
+                    }
+                    ErrorHandling::Comment => {
+                        let mermaid_code = mermaid_content
+                            .replace("```", "``\\`")
+                            .lines()
+                            .collect::<Vec<_>>()
+                            .join("\n> ");
+                        format!(
+                            r"> [!IMPORTANT]
 > **Mermaid diagram rendering failed during SSR because:**
 > ```raw
 > {e}
@@ -142,37 +514,235 @@ fn add_mermaid(content: &str, renderer: &renderer::Mermaid, config: &Config) ->
 > - Check your Mermaid code for any syntax errors by pasting it into the [Mermaid Playground](https://mermaid.live/).
 > - Look at the stdout log produced during mdbook build for more details
 >
-> <sub><sub>You are seeing this message because the setting `on-error` is `comment` and not `fail`.</sub></sub>",
-                            )
-                        }
+> <sub><sub>You are seeing this message because the setting `{setting}` is `comment` and not `fail`.</sub></sub>",
+                        )
                     }
                 }
-            };
+            }
+        };
 
-            mermaid_blocks.push((span, mermaid_code));
-            start_new_code_span = true;
-        }
+        mermaid_blocks.push((&block.span, mermaid_code));
     }
 
+    Ok(replace_blocks(content, mermaid_blocks))
+}
+
+/// Replaces the code blocks at the given spans of `content`, which are in document order
+fn replace_blocks<'a>(
+    content: &str,
+    replacements: impl IntoIterator<Item = (&'a Range<usize>, String)>,
+) -> String {
+    let replacements: Vec<_> = replacements.into_iter().collect();
     let mut content = content.to_string();
-    for (span, block) in mermaid_blocks.iter().rev() {
+    for (span, block) in replacements.iter().rev() {
         let pre_content = &content[0..span.start];
         let post_content = &content[span.end..];
         content = format!("{pre_content}\n{block}{post_content}");
     }
-    Ok(content)
+    content
+}
+
+/// Replaces all mermaid code blocks of `book` with plain code blocks showing their source
+fn show_sources(book: &mut Book) {
+    book.for_each_chapter_mut(|chapter| {
+        let blocks = find_mermaid_blocks(&chapter.content);
+        chapter.content = replace_blocks(
+            &chapter.content,
+            blocks
+                .iter()
+                .map(|block| (&block.span, code_block(&block.code))),
+        );
+    });
+}
+
+/// A plain code block showing the diagram source `code`
+///
+/// The fence is longer than any run of backticks in `code`, so that it cannot end the block early.
+fn code_block(code: &str) -> String {
+    let longest_run = code
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or_default();
+    let fence = "`".repeat(longest_run.max(2) + 1);
+    let newline = if code.ends_with('\n') { "" } else { "\n" };
+    format!("{fence}text\n{code}{newline}{fence}")
+}
+
+/// The output directory of the renderer the book is preprocessed for
+///
+/// Like mdbook, this only gives each renderer its own subdirectory if the book has several.
+fn build_dir(ctx: &PreprocessorContext) -> Result<PathBuf> {
+    let build_dir = ctx.root.join(&ctx.config.build.build_dir);
+    if ctx.config.outputs::<serde::de::IgnoredAny>()?.len() > 1 {
+        Ok(build_dir.join(&ctx.renderer))
+    } else {
+        Ok(build_dir)
+    }
+}
+
+/// Identifies `chapter` within the book, independently of the machine the book is built on
+fn chapter_id(chapter: &Chapter) -> String {
+    match chapter.path {
+        Some(ref path) => path.to_string_lossy().replace('\\', "/"),
+        // Draft chapters have no path
+        None => chapter.name.clone(),
+    }
+}
+
+/// A copy of the result of rendering a diagram, for another occurrence of the same diagram
+fn copy_rendered(rendered: &Result<String>) -> Result<String> {
+    match rendered {
+        Ok(svg) => Ok(svg.clone()),
+        Err(e) => match e.downcast_ref::<RenderTimeout>() {
+            Some(timeout) => Err((*timeout).into()),
+            None => Err(anyhow::anyhow!("{e:#}")),
+        },
+    }
+}
+
+/// Inserts the mermaid `directive` into `code`, right after its YAML frontmatter if it has one
+pub(crate) fn add_directive(code: &str, directive: &str) -> String {
+    // A `---` line, with either line ending
+    let is_fence = |line: &str| {
+        line.strip_suffix('\n')
+            .map(|line| line.strip_suffix('\r').unwrap_or(line))
+            == Some("---")
+    };
+    let mut lines = code.split_inclusive('\n');
+    if let Some(open) = lines.next().filter(|line| is_fence(line)) {
+        let mut end = open.len();
+        for line in lines {
+            end += line.len();
+            if is_fence(line) {
+                let (frontmatter, diagram) = code.split_at(end);
+                let newline = if line.ends_with("\r\n") { "\r\n" } else { "\n" };
+                return format!("{frontmatter}{directive}{newline}{diagram}");
+            }
+        }
+    }
+    format!("{directive}\n{code}")
+}
+
+/// Renders all mermaid blocks in `content` as one batch and splices them back in
+#[cfg(test)]
+fn add_mermaid(content: &str, renderer: &impl DiagramRenderer, config: &Config) -> Result<String> {
+    let blocks = find_mermaid_blocks(content);
+    let sources: Vec<&str> = blocks.iter().map(|block| block.code.as_str()).collect();
+    let svgs = renderer.render_many(&sources);
+    splice_mermaid(content, &blocks, svgs, config)
+}
+
+/// Renders `sources` on up to `jobs` threads
+///
+/// The results are returned in the same order as `sources`.
+fn render_concurrently<S: Sync, R: Send>(
+    sources: &[S],
+    jobs: usize,
+    render: impl Fn(&S) -> R + Sync,
+) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let mut rendered: Vec<(usize, R)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.clamp(1, sources.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut rendered = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(source) = sources.get(index) else {
+                            break rendered;
+                        };
+                        rendered.push((index, render(source)));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("render worker panicked"))
+            .collect()
+    });
+    rendered.sort_by_key(|(index, _)| *index);
+    rendered.into_iter().map(|(_, result)| result).collect()
 }
 
 #[cfg(test)]
 mod test {
+    use std::num::NonZeroUsize;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
     use pretty_assertions::assert_eq;
 
-    use super::{add_mermaid, renderer};
-    use crate::config::Config;
+    use mdbook_preprocessor::book::{Book, BookItem, Chapter};
+    use mdbook_preprocessor::{Preprocessor, PreprocessorContext};
+
+    use super::{
+        Mermaid, Result, add_directive, add_mermaid, build_dir, code_block, render_concurrently,
+    };
+    use crate::config::{Config, ErrorHandling, Output};
+    use crate::renderer::{DiagramRenderer, RenderTimeout};
+
+    /// Renders diagrams without a browser, rejecting unknown diagram types like mermaid does
+    ///
+    /// Gantt charts stand in for diagrams which take too long to render.
+    struct FakeRenderer;
+
+    impl DiagramRenderer for FakeRenderer {
+        fn render(&self, input: &str) -> Result<String> {
+            // Skips directives, such as the seeds of reproducible builds
+            let diagram = input.lines().filter(|line| !line.starts_with("%%"));
+            match diagram.flat_map(str::split_whitespace).next() {
+                Some(kind @ ("graph" | "flowchart" | "classDiagram" | "sequenceDiagram")) => {
+                    Ok(format!(r#"<svg aria-roledescription="{kind}"></svg>"#))
+                }
+                Some("gantt") => Err(RenderTimeout(std::time::Duration::from_secs(10)).into()),
+                _ => anyhow::bail!("No diagram type detected matching given configuration"),
+            }
+        }
+    }
+
+    /// Runs `preprocessor` for `renderer` on a book of `chapters`, given by their path and
+    /// content, returning the preprocessed content of each chapter
+    fn preprocess(
+        preprocessor: &Mermaid,
+        renderer: &str,
+        chapters: &[(&str, &str)],
+    ) -> Vec<String> {
+        try_preprocess(Path::new("."), preprocessor, renderer, chapters).unwrap()
+    }
+
+    /// Like [`preprocess`], for the book at `root`, passing on the error of the preprocessor
+    fn try_preprocess(
+        root: &Path,
+        preprocessor: &Mermaid,
+        renderer: &str,
+        chapters: &[(&str, &str)],
+    ) -> Result<Vec<String>> {
+        let mut book = Book::new();
+        for &(path, content) in chapters {
+            let name = path.trim_end_matches(".md");
+            book.push_item(Chapter::new(name, content.to_string(), path, vec![]));
+        }
+        let ctx = PreprocessorContext::new(
+            root.into(),
+            mdbook_preprocessor::config::Config::default(),
+            renderer.to_string(),
+        );
+        let book = preprocessor.run(&ctx, book)?;
+        Ok(book
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(chapter.content.clone()),
+                _ => None,
+            })
+            .collect())
+    }
 
     #[test]
     fn adds_mermaid() {
-        let mermaid = renderer::Mermaid::try_init().unwrap();
+        let mermaid = FakeRenderer;
         let config = Config::default();
         let content = r#"# Chapter
 
@@ -197,7 +767,7 @@ Text
     fn leaves_tables_untouched() {
         // Regression test.
         // Previously we forgot to enable the same markdwon extensions as mdbook itself.
-        let mermaid = renderer::Mermaid::try_init().unwrap();
+        let mermaid = FakeRenderer;
         let config = Config::default();
 
         let content = r#"# Heading
@@ -221,7 +791,7 @@ Text
     fn leaves_html_untouched() {
         // Regression test.
         // Don't remove important newlines for syntax nested inside HTML
-        let mermaid = renderer::Mermaid::try_init().unwrap();
+        let mermaid = FakeRenderer;
         let config = Config::default();
 
         let content = r#"# Heading
@@ -249,7 +819,7 @@ Text
     fn html_in_list() {
         // Regression test.
         // Don't remove important newlines for syntax nested inside HTML
-        let mermaid = renderer::Mermaid::try_init().unwrap();
+        let mermaid = FakeRenderer;
         let config = Config::default();
 
         let content = r#"# Heading
@@ -276,7 +846,7 @@ Text
     #[test]
     fn escape_in_mermaid_block() {
         let _ = env_logger::try_init();
-        let mermaid = renderer::Mermaid::try_init().unwrap();
+        let mermaid = FakeRenderer;
         let config = Config::default();
         let content = r#"
 ```mermaid
@@ -301,7 +871,7 @@ hello
     #[test]
     fn more_backticks() {
         let _ = env_logger::try_init();
-        let mermaid = renderer::Mermaid::try_init().unwrap();
+        let mermaid = FakeRenderer;
         let config = Config::default();
         let content = r#"# Chapter
 
@@ -325,7 +895,7 @@ Text
     #[test]
     fn crlf_line_endings() {
         let _ = env_logger::try_init();
-        let mermaid = renderer::Mermaid::try_init().unwrap();
+        let mermaid = FakeRenderer;
         let config = Config::default();
         let content = "# Chapter\r\n\r\n````mermaid\r\n\r\ngraph TD\r\nA --> B\r\n````";
 
@@ -339,7 +909,7 @@ Text
     #[test]
     fn test_on_error_fail() {
         let _ = env_logger::try_init();
-        let mermaid = renderer::Mermaid::try_init().unwrap();
+        let mermaid = FakeRenderer;
         let mut config = Config::default();
         config.on_error = crate::config::ErrorHandling::Fail;
 
@@ -360,7 +930,7 @@ A --> B
     #[test]
     fn test_on_error_comment() {
         let _ = env_logger::try_init();
-        let mermaid = renderer::Mermaid::try_init().unwrap();
+        let mermaid = FakeRenderer;
         let mut config = Config::default();
         config.on_error = crate::config::ErrorHandling::Comment;
 
@@ -380,4 +950,505 @@ A --> B
         assert!(output.contains("[!IMPORTANT]"));
         assert!(output.contains("Mermaid diagram rendering failed during SSR"));
     }
+
+    #[test]
+    fn test_on_timeout() {
+        let mermaid = FakeRenderer;
+        let content = "```mermaid\ngantt\n```\n\n```mermaid\ngrph TD\n```\n";
+
+        // Timeouts are handled like any other error by default
+        let mut config = Config {
+            on_error: ErrorHandling::Comment,
+            ..Config::default()
+        };
+        let output = add_mermaid(content, &mermaid, &config).unwrap();
+        assert!(output.contains("> Timed out after 10s\n"));
+        assert!(output.contains("the setting `on-timeout` is `comment`"));
+        assert!(output.contains("the setting `on-error` is `comment`"));
+
+        config.on_timeout = Some(ErrorHandling::Fail);
+        let err = add_mermaid(content, &mermaid, &config).unwrap_err();
+        assert!(err.is::<RenderTimeout>());
+
+        config.on_error = ErrorHandling::Fail;
+        config.on_timeout = Some(ErrorHandling::Comment);
+        assert!(add_mermaid("```mermaid\ngantt\n```\n", &mermaid, &config).is_ok());
+    }
+
+    #[test]
+    fn render_concurrently_keeps_order() {
+        let sources: Vec<String> = (0..50).map(|i| format!("graph TD\nA --> B{i}")).collect();
+        let sources: Vec<&str> = sources.iter().map(String::as_str).collect();
+
+        let rendered = render_concurrently(&sources, 8, |input| {
+            // Finish the renders out of order
+            std::thread::sleep(std::time::Duration::from_millis(input.len() as u64 % 3));
+            format!("<svg>{input}</svg>")
+        });
+
+        let expected: Vec<String> = sources.iter().map(|s| format!("<svg>{s}</svg>")).collect();
+        assert_eq!(expected, rendered);
+    }
+
+    #[test]
+    fn does_not_launch_browser_without_diagrams() {
+        let config = Config {
+            // Launching a browser would fail with this, so the preprocessor must not try
+            chrome_path: Some("/nonexistent/chrome".into()),
+            cache: false,
+            ..Config::default()
+        };
+        let preprocessor = Mermaid::new(config);
+
+        let content = "# Chapter\n\n```rust\nfn main() {}\n```\n";
+        assert_eq!(
+            preprocess(&preprocessor, "html", &[("chapter.md", content)]),
+            [content]
+        );
+    }
+
+    #[test]
+    fn rejects_missing_renders() {
+        /// Forgets the last diagram of every batch
+        struct ForgetfulRenderer;
+
+        impl DiagramRenderer for ForgetfulRenderer {
+            fn render(&self, input: &str) -> Result<String> {
+                FakeRenderer.render(input)
+            }
+
+            fn render_many(&self, inputs: &[&str]) -> Vec<Result<String>> {
+                let mut rendered: Vec<_> = inputs.iter().map(|input| self.render(input)).collect();
+                rendered.pop();
+                rendered
+            }
+        }
+
+        let config = Config {
+            cache: false,
+            ..Config::default()
+        };
+        let preprocessor = Mermaid::with_renderer(config, ForgetfulRenderer);
+
+        let rendered = preprocessor.render_many(&["graph TD\nA --> B", "graph TD\nA --> C"]);
+        assert_eq!(rendered.len(), 2);
+        for result in rendered {
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "The renderer answered 1 of 2 diagrams"
+            );
+        }
+    }
+
+    #[test]
+    fn deduplicates_diagrams() {
+        /// Counts the diagrams it renders
+        struct CountingRenderer(Arc<AtomicUsize>);
+
+        impl DiagramRenderer for CountingRenderer {
+            fn render(&self, input: &str) -> Result<String> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                FakeRenderer.render(input)
+            }
+        }
+
+        let renders = Arc::new(AtomicUsize::new(0));
+        let config = Config {
+            cache: false,
+            ..Config::default()
+        };
+        let preprocessor = Mermaid::with_renderer(config, CountingRenderer(Arc::clone(&renders)));
+
+        let overview = "```mermaid\ngraph TD\nA --> B\n```\n";
+        let chapter = |name: &str| {
+            format!(
+                "# {name}\n\n{overview}\n```mermaid\nsequenceDiagram\n{name}->>B: hi\n```\n\n{overview}"
+            )
+        };
+        let (one, two, three) = (chapter("one"), chapter("two"), chapter("three"));
+        let chapters = [("one.md", &*one), ("two.md", &*two), ("three.md", &*three)];
+
+        let contents = preprocess(&preprocessor, "html", &chapters);
+        // The overview once, and the sequence diagram of each chapter
+        assert_eq!(renders.load(Ordering::Relaxed), 4);
+        for content in contents {
+            assert_eq!(
+                content
+                    .matches(r#"<svg aria-roledescription="graph">"#)
+                    .count(),
+                2
+            );
+            assert_eq!(
+                content
+                    .matches(r#"<svg aria-roledescription="sequenceDiagram">"#)
+                    .count(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn stops_rendering_after_failure() {
+        /// Counts the diagrams it renders
+        struct CountingRenderer(Arc<AtomicUsize>);
+
+        impl DiagramRenderer for CountingRenderer {
+            fn render(&self, input: &str) -> Result<String> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                FakeRenderer.render(input)
+            }
+        }
+
+        let renders = Arc::new(AtomicUsize::new(0));
+        let config = Config {
+            cache: false,
+            jobs: Some(NonZeroUsize::MIN),
+            on_error: ErrorHandling::Fail,
+            ..Config::default()
+        };
+        let preprocessor = Mermaid::with_renderer(config, CountingRenderer(Arc::clone(&renders)));
+
+        // Distinct diagrams, as identical ones would be rendered only once anyway
+        let chapters = [
+            ("one.md", "```mermaid\nnonsense one\n```\n"),
+            ("two.md", "```mermaid\nnonsense two\n```\n"),
+            ("three.md", "```mermaid\nnonsense three\n```\n"),
+        ];
+
+        let error = try_preprocess(Path::new("."), &preprocessor, "html", &chapters).unwrap_err();
+        assert!(error.to_string().contains("No diagram type detected"));
+        // The first chapter already fails the build
+        assert_eq!(renders.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn keeps_failed_launch() {
+        let config = Config {
+            chrome_path: Some("/nonexistent/chrome".into()),
+            cache: false,
+            ..Config::default()
+        };
+        let preprocessor = Mermaid::new(config);
+
+        let error = preprocessor.renderer().err().unwrap().to_string();
+        assert!(matches!(
+            &*preprocessor.renderer.lock().unwrap(),
+            Some(Err(kept)) if *kept == error
+        ));
+        // Later batches get the same error, without launching again
+        assert_eq!(error, preprocessor.renderer().err().unwrap().to_string());
+    }
+
+    #[test]
+    fn namespaces_ids_per_occurrence() {
+        struct IdRenderer;
+
+        impl DiagramRenderer for IdRenderer {
+            fn render(&self, _input: &str) -> Result<String> {
+                Ok(r#"<svg id="root"><marker id="arrowhead"/></svg>"#.to_string())
+            }
+        }
+
+        let config = Config {
+            cache: false,
+            ..Config::default()
+        };
+        let preprocessor = Mermaid::with_renderer(config, IdRenderer);
+
+        let diagram = "```mermaid\ngraph TD\nA --> B\n```\n";
+        let content = format!("{diagram}\n{diagram}");
+        let chapters = [("one.md", content.as_str()), ("two.md", content.as_str())];
+
+        let mut ids = std::collections::HashSet::new();
+        for content in preprocess(&preprocessor, "html", &chapters) {
+            for id in content.split(" id=\"").skip(1) {
+                let id = id.split('"').next().unwrap();
+                assert!(ids.insert(id.to_string()), "{id} is not unique");
+            }
+        }
+        assert_eq!(ids.len(), 8);
+    }
+
+    #[test]
+    fn test_add_directive() {
+        assert_eq!(
+            add_directive("graph TD\nA --> B", "%%{init: {}}%%"),
+            "%%{init: {}}%%\ngraph TD\nA --> B"
+        );
+        assert_eq!(
+            add_directive("---\ntitle: Flow\n---\ngraph TD\nA --> B", "%%{init: {}}%%"),
+            "---\ntitle: Flow\n---\n%%{init: {}}%%\ngraph TD\nA --> B"
+        );
+        assert_eq!(
+            add_directive(
+                "---\r\ntitle: Flow\r\n---\r\ngraph TD\r\nA --> B",
+                "%%{init: {}}%%"
+            ),
+            "---\r\ntitle: Flow\r\n---\r\n%%{init: {}}%%\r\ngraph TD\r\nA --> B"
+        );
+        // An unterminated frontmatter is left to mermaid to report
+        assert_eq!(
+            add_directive("---\ntitle: Flow\ngraph TD", "%%{init: {}}%%"),
+            "%%{init: {}}%%\n---\ntitle: Flow\ngraph TD"
+        );
+    }
+
+    #[test]
+    fn does_not_rewrite_cache_hits() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache_dir: Some(dir.path().into()),
+            ..Config::default()
+        };
+        let build = || {
+            Mermaid::with_renderer(config.clone(), FakeRenderer).render_many(&["graph TD\nA --> B"])
+        };
+        assert!(build()[0].is_ok());
+
+        let namespace = std::fs::read_dir(dir.path()).unwrap().next().unwrap();
+        let entry = std::fs::read_dir(namespace.unwrap().path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_hours(24);
+        std::fs::File::options()
+            .write(true)
+            .open(&entry)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        assert!(build()[0].is_ok());
+        assert_eq!(
+            std::fs::metadata(&entry).unwrap().modified().unwrap(),
+            modified
+        );
+    }
+
+    #[test]
+    fn seeds_diagrams_by_position() {
+        /// Records the sources it renders
+        struct RecordingRenderer(Arc<std::sync::Mutex<Vec<String>>>);
+
+        impl DiagramRenderer for RecordingRenderer {
+            fn render(&self, input: &str) -> Result<String> {
+                self.0.lock().unwrap().push(input.to_string());
+                FakeRenderer.render(input)
+            }
+        }
+
+        let build = |config: Config| {
+            let sources = Arc::new(std::sync::Mutex::new(vec![]));
+            let preprocessor =
+                Mermaid::with_renderer(config, RecordingRenderer(Arc::clone(&sources)));
+            let shared = "```mermaid\ngraph TD\nA --> B\n```\n";
+            preprocess(
+                &preprocessor,
+                "html",
+                &[
+                    (
+                        "one.md",
+                        &format!("{shared}\n```mermaid\ngraph TD\none --> B\n```\n"),
+                    ),
+                    (
+                        "two.md",
+                        &format!("{shared}\n```mermaid\ngraph TD\ntwo --> B\n```\n"),
+                    ),
+                ],
+            );
+            let mut sources = std::mem::take(&mut *sources.lock().unwrap());
+            sources.sort();
+            sources
+        };
+        let config = Config {
+            cache: false,
+            reproducible: true,
+            ..Config::default()
+        };
+
+        let sources = build(config.clone());
+        // The shared diagram is rendered once, and every render is seeded differently
+        assert_eq!(sources.len(), 3);
+        let seeds: std::collections::HashSet<&str> = sources
+            .iter()
+            .filter_map(|source| source.lines().next())
+            .collect();
+        assert_eq!(seeds.len(), 3);
+        assert!(seeds.iter().all(|seed| seed.starts_with("%%{init: ")));
+        // The seeds do not change between builds
+        assert_eq!(build(config.clone()), sources);
+
+        let sources = build(Config {
+            reproducible: false,
+            ..config
+        });
+        assert!(sources.iter().all(|source| source.starts_with("graph TD")));
+    }
+
+    #[test]
+    fn writes_large_diagrams_to_files() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: false,
+            output: Output::File,
+            inline_threshold: 45,
+            ..Config::default()
+        };
+        let preprocessor = Mermaid::with_renderer(config, FakeRenderer);
+
+        let chapters = [(
+            "guide/setup.md",
+            "```mermaid\ngraph TD\nA --> B\n```\n\n```mermaid\nsequenceDiagram\nA->>B: hi\n```\n",
+        )];
+
+        let content = try_preprocess(root.path(), &preprocessor, "html", &chapters).unwrap();
+        let svg = crate::svg::to_xml(r#"<svg aria-roledescription="sequenceDiagram"></svg>"#);
+        let name = format!("{}.svg", crate::cache::digest(&[&svg]));
+        // Small diagrams stay inline
+        assert!(content[0].contains(r#"<svg aria-roledescription="graph"></svg>"#));
+        assert!(content[0].contains(&format!(
+            r#"<img src="../mermaid-ssr/html/{name}" alt="Mermaid diagram" loading="lazy"/>"#
+        )));
+        let path = root.path().join("src/mermaid-ssr/html").join(name);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), svg);
+
+        // Switching back to inlining removes the files
+        let config = Config {
+            cache: false,
+            inline_threshold: 45,
+            ..Config::default()
+        };
+        let preprocessor = Mermaid::with_renderer(config, FakeRenderer);
+        try_preprocess(root.path(), &preprocessor, "html", &chapters).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_build_dir() {
+        let ctx = |config| PreprocessorContext::new("/book".into(), config, "typst".to_string());
+        let mut config = mdbook_preprocessor::config::Config::default();
+        config.set("output.typst", serde_json::json!({})).unwrap();
+        assert_eq!(
+            build_dir(&ctx(config.clone())).unwrap(),
+            Path::new("/book/book")
+        );
+
+        config.set("output.html", serde_json::json!({})).unwrap();
+        assert_eq!(
+            build_dir(&ctx(config)).unwrap(),
+            Path::new("/book/book/typst")
+        );
+    }
+
+    #[test]
+    fn rejects_pdfs_for_cleaning_renderers() {
+        let config = Config {
+            cache: false,
+            output: Output::Pdf,
+            renderers: [("typst".to_string(), Output::Pdf)].into(),
+            ..Config::default()
+        };
+        let preprocessor = Mermaid::with_renderer(config, FakeRenderer);
+
+        assert!(preprocessor.supports_renderer("typst").unwrap());
+        let error = preprocessor.supports_renderer("html").unwrap_err();
+        assert!(error.to_string().contains("empties its output directory"));
+
+        assert!(try_preprocess(Path::new("."), &preprocessor, "html", &[]).is_err());
+    }
+
+    #[test]
+    fn chooses_output_per_renderer() {
+        let config = Config {
+            cache: false,
+            renderers: [("markdown".to_string(), Output::Code)].into(),
+            ..Config::default()
+        };
+        let preprocessor = Mermaid::with_renderer(config, FakeRenderer);
+        assert!(preprocessor.supports_renderer("html").unwrap());
+        assert!(preprocessor.supports_renderer("markdown").unwrap());
+        assert!(!preprocessor.supports_renderer("epub").unwrap());
+
+        let content = "# Chapter\n\n```mermaid\ngraph TD\nA --> B\n```\n\nText\n";
+        let chapters = [("chapter.md", content)];
+
+        assert!(
+            preprocess(&preprocessor, "html", &chapters)[0]
+                .contains(r#"<svg aria-roledescription="graph"></svg>"#)
+        );
+        assert_eq!(
+            preprocess(&preprocessor, "markdown", &chapters),
+            ["# Chapter\n\n\n```text\ngraph TD\nA --> B\n```\n\nText\n"]
+        );
+        assert_eq!(preprocess(&preprocessor, "epub", &chapters), [content]);
+    }
+
+    #[test]
+    fn serializes_xml_for_epub() {
+        struct HtmlLabelRenderer;
+
+        impl DiagramRenderer for HtmlLabelRenderer {
+            fn render(&self, _input: &str) -> Result<String> {
+                Ok("<svg><foreignObject><div>A<br>B</div></foreignObject></svg>".to_string())
+            }
+        }
+
+        let config = Config {
+            cache: false,
+            renderers: [
+                ("html".to_string(), Output::Inline),
+                ("epub".to_string(), Output::Inline),
+            ]
+            .into(),
+            ..Config::default()
+        };
+        let preprocessor = Mermaid::with_renderer(config, HtmlLabelRenderer);
+        let chapters = [("chapter.md", "```mermaid\ngraph TD\n```\n")];
+
+        assert!(preprocess(&preprocessor, "html", &chapters)[0].contains("A<br>B"));
+        assert!(
+            preprocess(&preprocessor, "epub", &chapters)[0].contains(
+                r#"<foreignObject><div xmlns="http://www.w3.org/1999/xhtml">A<br/>B</div>"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_code_block() {
+        assert_eq!(code_block("graph TD\n"), "```text\ngraph TD\n```");
+        assert_eq!(
+            code_block("graph TD\nA[```] --> B"),
+            "````text\ngraph TD\nA[```] --> B\n````"
+        );
+    }
+
+    #[test]
+    fn renders_all_chapters() {
+        let config = Config {
+            cache: false,
+            ..Config::default()
+        };
+        let preprocessor = Mermaid::with_renderer(config, FakeRenderer);
+
+        let chapters = [
+            (
+                "one.md",
+                "# one\n\n```mermaid\ngraph TD\nA --> B\n```\n\nText\n",
+            ),
+            (
+                "two.md",
+                "# two\n\n```mermaid\nsequenceDiagram\nA->>B: hi\n```\n\nText\n",
+            ),
+        ];
+
+        assert_eq!(
+            preprocess(&preprocessor, "html", &chapters),
+            [
+                "# one\n\n\n<svg aria-roledescription=\"graph\"></svg>\n\n\n\nText\n",
+                "# two\n\n\n<svg aria-roledescription=\"sequenceDiagram\"></svg>\n\n\n\nText\n",
+            ]
+        );
+    }
 }
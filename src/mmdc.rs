@@ -0,0 +1,276 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use tempfile::TempDir;
+
+use crate::config::Config;
+use crate::renderer::{DiagramRenderer, RenderTimeout};
+
+/// Renders diagrams via a local mermaid-cli (`mmdc`) executable
+///
+/// Every diagram is rendered by a separate `mmdc` process.
+/// The configuration is mapped onto the mermaid and puppeteer configuration files of mermaid-cli.
+pub struct Mmdc {
+    path: PathBuf,
+    /// Holds the configuration files passed to every invocation
+    config_dir: TempDir,
+    render_timeout: Duration,
+}
+
+impl Mmdc {
+    /// Initializes the backend with a configuration object
+    ///
+    /// Fails if `mmdc` cannot be executed.
+    pub fn try_init_with_config(config: &Config) -> Result<Self> {
+        let path = config
+            .mmdc_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("mmdc"));
+
+        let config_dir = tempfile::tempdir()?;
+        fs::write(
+            config_dir.path().join("mermaid.json"),
+            config.build_mermaid_config_json(),
+        )?;
+        fs::write(
+            config_dir.path().join("puppeteer.json"),
+            build_puppeteer_config_json(config),
+        )?;
+
+        let mmdc = Self {
+            path,
+            config_dir,
+            render_timeout: config.render_timeout(),
+        };
+        let version = mmdc
+            .run(&["--version"], config.launch_timeout())
+            .with_context(|| format!("Failed to run {}", mmdc.path.display()))?;
+        log::info!("Rendering with mermaid-cli {}", version.trim());
+        Ok(mmdc)
+    }
+
+    /// Runs `mmdc` with `args`, returning its stdout
+    ///
+    /// The process is killed if it does not finish within `timeout`.
+    fn run(&self, args: &[&str], timeout: Duration) -> Result<String> {
+        let mut child = Command::new(&self.path)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // A full pipe would block the process, so its output is read while it runs
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                // The readers are left behind, as the browser mmdc launched may keep the pipes open
+                bail!(RenderTimeout(timeout));
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        let stdout = stdout.join().expect("pipe reader panicked");
+        let stderr = stderr.join().expect("pipe reader panicked");
+        if !status.success() {
+            bail!(
+                "mmdc failed with {status}: {}",
+                String::from_utf8_lossy(&stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&stdout).into_owned())
+    }
+
+    fn config_file(&self, name: &str) -> PathBuf {
+        self.config_dir.path().join(name)
+    }
+}
+
+impl DiagramRenderer for Mmdc {
+    fn render(&self, input: &str) -> Result<String> {
+        let dir = tempfile::tempdir()?;
+        let input_file = dir.path().join("diagram.mmd");
+        let output_file = dir.path().join("diagram.svg");
+        fs::write(&input_file, input)?;
+
+        let mermaid_config = self.config_file("mermaid.json");
+        let puppeteer_config = self.config_file("puppeteer.json");
+        self.run(
+            &[
+                "--quiet",
+                "--input",
+                path_arg(&input_file)?,
+                "--output",
+                path_arg(&output_file)?,
+                "--configFile",
+                path_arg(&mermaid_config)?,
+                "--puppeteerConfigFile",
+                path_arg(&puppeteer_config)?,
+            ],
+            self.render_timeout,
+        )?;
+
+        let svg = fs::read_to_string(&output_file)
+            .context("Failed to compile Mermaid diagram: mmdc did not produce an SVG")?;
+        if svg.is_empty() {
+            bail!("Failed to compile Mermaid diagram: empty result");
+        }
+        Ok(svg)
+    }
+}
+
+/// Reads `pipe` to its end on a separate thread
+fn drain(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+        output
+    })
+}
+
+fn path_arg(path: &Path) -> Result<&str> {
+    path.to_str()
+        .with_context(|| format!("{} is not valid UTF-8", path.display()))
+}
+
+/// Maps the browser related options onto a puppeteer launch configuration
+fn build_puppeteer_config_json(config: &Config) -> String {
+    let mut puppeteer = serde_json::Map::new();
+    if let Some(ref chrome_path) = config.chrome_path {
+        puppeteer.insert(
+            "executablePath".to_string(),
+            chrome_path.to_string_lossy().into(),
+        );
+    }
+    let mut args = config.chrome_args.clone();
+    if !config.sandbox {
+        args.extend([
+            "--no-sandbox".to_string(),
+            "--disable-setuid-sandbox".to_string(),
+        ]);
+    }
+    if !args.is_empty() {
+        puppeteer.insert("args".to_string(), args.into());
+    }
+    serde_json::Value::Object(puppeteer).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_build_puppeteer_config_json() {
+        let mut config = Config::default();
+        assert_eq!(build_puppeteer_config_json(&config), "{}");
+
+        config.chrome_path = Some(PathBuf::from("/usr/bin/chromium"));
+        assert_eq!(
+            build_puppeteer_config_json(&config),
+            r#"{"executablePath":"/usr/bin/chromium"}"#
+        );
+
+        config.chrome_args = vec!["--disable-dev-shm-usage".to_string()];
+        config.sandbox = false;
+        assert_eq!(
+            build_puppeteer_config_json(&config),
+            r#"{"args":["--disable-dev-shm-usage","--no-sandbox","--disable-setuid-sandbox"],"executablePath":"/usr/bin/chromium"}"#
+        );
+    }
+
+    #[test]
+    fn missing_mmdc_fails_on_init() {
+        let config = Config {
+            mmdc_path: Some(PathBuf::from("/nonexistent/mmdc")),
+            ..Config::default()
+        };
+        let err = Mmdc::try_init_with_config(&config).err().unwrap();
+        assert_eq!(err.to_string(), "Failed to run /nonexistent/mmdc");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn renders_via_mmdc() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stands in for mermaid-cli, which rejects everything but flowcharts
+        let dir = tempfile::tempdir().unwrap();
+        let mmdc_path = dir.path().join("mmdc");
+        fs::write(
+            &mmdc_path,
+            r#"#!/bin/sh
+[ "$1" = "--version" ] && { echo 11.4.0; exit 0; }
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --input) input="$2"; shift ;;
+        --output) output="$2"; shift ;;
+    esac
+    shift
+done
+grep -q '^graph' "$input" || { echo "Parse error on line 1" >&2; exit 1; }
+echo '<svg id="mmdc"></svg>' > "$output"
+"#,
+        )
+        .unwrap();
+        fs::set_permissions(&mmdc_path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = Config {
+            mmdc_path: Some(mmdc_path),
+            ..Config::default()
+        };
+        let mmdc = Mmdc::try_init_with_config(&config).unwrap();
+        assert_eq!(
+            mmdc.render("graph TD\nA-->B").unwrap(),
+            "<svg id=\"mmdc\"></svg>\n"
+        );
+        let err = mmdc.render("grph TD\nA-->B").unwrap_err();
+        assert!(err.to_string().contains("Parse error on line 1"), "{err}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn does_not_block_on_verbose_output() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Writes much more than fits into a pipe buffer before failing
+        let dir = tempfile::tempdir().unwrap();
+        let mmdc_path = dir.path().join("mmdc");
+        fs::write(
+            &mmdc_path,
+            r#"#!/bin/sh
+[ "$1" = "--version" ] && { echo 11.4.0; exit 0; }
+head -c 1000000 /dev/zero | tr '\0' x
+head -c 1000000 /dev/zero | tr '\0' y >&2
+echo "Parse error on line 1" >&2
+exit 1
+"#,
+        )
+        .unwrap();
+        fs::set_permissions(&mmdc_path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = Config {
+            mmdc_path: Some(mmdc_path),
+            render_timeout: Some(Duration::from_secs(10)),
+            ..Config::default()
+        };
+        let mmdc = Mmdc::try_init_with_config(&config).unwrap();
+        let err = mmdc.render("graph TD\nA-->B").unwrap_err();
+        assert!(!err.is::<RenderTimeout>());
+        assert!(err.to_string().ends_with("Parse error on line 1"));
+    }
+}
@@ -0,0 +1,413 @@
+//! Writing rendered diagrams as separate files, instead of inlining them into the chapters
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{cache, svg};
+
+/// Directory within the source directory of the book which the diagram files are written to,
+/// with a subdirectory per renderer, or within the output directory of a renderer
+pub const OUTPUT_DIR: &str = "mermaid-ssr";
+
+/// File listing the diagram files written into a directory, which are the only ones ever removed
+/// from it
+const MANIFEST: &str = ".manifest";
+
+/// Writes diagrams as content-hashed files into a directory which belongs to this preprocessor
+///
+/// Within the source directory of the book, mdbook copies them into the output along with all
+/// other assets. Files are only written if
+/// their content changed, so that `mdbook serve` does not rebuild the book in a loop.
+pub struct DiagramFiles {
+    dir: PathBuf,
+    /// `dir` relative to the directory the chapters are in, as a URL path
+    url_dir: String,
+    /// Names of the files the previous builds wrote, according to the manifest
+    written: HashSet<String>,
+    /// Names of the files the current build references
+    used: HashSet<String>,
+}
+
+impl DiagramFiles {
+    /// Writes into `{src_dir}/mermaid-ssr/{renderer}`
+    ///
+    /// Each renderer gets its own directory, so that renderers with different output modes do
+    /// not remove each other's files.
+    #[must_use]
+    pub fn new(src_dir: &Path, renderer: &str) -> Self {
+        Self::with_dir(
+            src_dir.join(OUTPUT_DIR).join(renderer),
+            format!("{OUTPUT_DIR}/{renderer}"),
+        )
+    }
+
+    /// Writes into `{build_dir}/mermaid-ssr`, where `build_dir` is the output directory of the
+    /// renderer
+    ///
+    /// This suits renderers which read such files from disk instead of copying the source
+    /// directory, like Typst or LaTeX backends. The files are referenced relative to the chapters
+    /// as if those were in `build_dir` as well.
+    #[must_use]
+    pub fn in_build_dir(build_dir: &Path) -> Self {
+        Self::with_dir(build_dir.join(OUTPUT_DIR), OUTPUT_DIR.to_string())
+    }
+
+    fn with_dir(dir: PathBuf, url_dir: String) -> Self {
+        let written = fs::read_to_string(dir.join(MANIFEST))
+            .map(|manifest| {
+                manifest
+                    .lines()
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            dir,
+            url_dir,
+            written,
+            used: HashSet::new(),
+        }
+    }
+
+    /// Writes `svg` to a file, returning the `<img>` which displays it in the chapter at
+    /// `chapter_path`
+    ///
+    /// `chapter_path` is relative to the source directory of the book.
+    pub fn write_svg(&mut self, svg: &str, chapter_path: Option<&Path>) -> Result<String> {
+        let name = self.write_standalone_svg(svg)?;
+        Ok(img(&self.src(&name, chapter_path), svg))
+    }
+
+    /// Writes `svg` to a file of its own, returning the name of the file
+    ///
+    /// Browsers parse standalone SVGs as XML, unlike the HTML serialization mermaid renders.
+    fn write_standalone_svg(&mut self, svg: &str) -> Result<String> {
+        let svg = svg::to_xml(svg);
+        let name = format!("{}.svg", cache::digest(&[&svg]));
+        self.write(&name, svg.as_bytes())?;
+        Ok(name)
+    }
+
+    /// Writes a PNG of `svg` to a file, returning the `<img>` which displays it in the chapter at
+    /// `chapter_path`
+    ///
+    /// `rasterize` is only called if no PNG of `svg` at `device_scale_factor` was written before.
+    /// With `picture`, the SVG is written as well, and the `<img>` wrapped in a `<picture>`
+    /// which browsers supporting SVG prefer.
+    pub fn write_png(
+        &mut self,
+        svg: &str,
+        device_scale_factor: f64,
+        picture: bool,
+        chapter_path: Option<&Path>,
+        rasterize: impl FnOnce() -> Result<Vec<u8>>,
+    ) -> Result<String> {
+        let name = format!(
+            "{}.png",
+            cache::digest(&[svg, &device_scale_factor.to_string()])
+        );
+        self.write_once(&name, rasterize)?;
+        let img = img(&self.src(&name, chapter_path), svg);
+        if !picture {
+            return Ok(img);
+        }
+
+        let svg_name = self.write_standalone_svg(svg)?;
+        Ok(format!(
+            r#"<picture><source srcset="{}" type="image/svg+xml"/>{img}</picture>"#,
+            self.src(&svg_name, chapter_path)
+        ))
+    }
+
+    /// Writes a PDF of `svg` to a file, returning the Markdown image which displays it in the
+    /// chapter at `chapter_path`
+    ///
+    /// `print` is only called if no PDF of `svg` was written before. Unlike HTML, Markdown images
+    /// are understood by the backends which turn books into print documents.
+    pub fn write_pdf(
+        &mut self,
+        svg: &str,
+        chapter_path: Option<&Path>,
+        print: impl FnOnce() -> Result<Vec<u8>>,
+    ) -> Result<String> {
+        let name = format!("{}.pdf", cache::digest(&[svg]));
+        self.write_once(&name, print)?;
+        let alt = svg::title(svg)
+            .unwrap_or("Mermaid diagram")
+            .replace('[', "\\[")
+            .replace(']', "\\]");
+        // Angle brackets allow spaces in the path
+        Ok(format!("![{alt}](<{}>)", self.src(&name, chapter_path)))
+    }
+
+    /// Writes the file `name` with the contents produced by `produce`, unless it exists already
+    ///
+    /// Suits files whose name is derived from everything they are produced from.
+    fn write_once(&mut self, name: &str, produce: impl FnOnce() -> Result<Vec<u8>>) -> Result<()> {
+        if self.dir.join(name).exists() {
+            self.used.insert(name.to_string());
+            Ok(())
+        } else {
+            self.write(name, &produce()?)
+        }
+    }
+
+    /// Writes the file `name`, unless it already has `contents`
+    fn write(&mut self, name: &str, contents: &[u8]) -> Result<()> {
+        let path = self.dir.join(name);
+        if fs::read(&path).ok().as_deref() != Some(contents) {
+            self.create_dir()?;
+            // Write to a temporary file first, so that an interrupted build never leaves a
+            // truncated file behind, which `write_once` would keep
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, contents)
+                .with_context(|| format!("Failed to write {}", tmp.display()))?;
+            fs::rename(&tmp, &path)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+        self.used.insert(name.to_string());
+        Ok(())
+    }
+
+    /// Creates the directory, which version control is told to ignore
+    fn create_dir(&self) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let gitignore = self.dir.join(".gitignore");
+        if !gitignore.exists() {
+            fs::write(&gitignore, "*\n")
+                .with_context(|| format!("Failed to write {}", gitignore.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Removes the diagram files which previous builds wrote, but the current build does not
+    /// reference anymore, and records the files of the current build in the manifest
+    ///
+    /// Files which are not listed in the manifest are never removed. Returns the number of
+    /// removed files.
+    pub fn evict_unused(&self) -> Result<usize> {
+        let mut evicted = 0;
+        for name in self.written.difference(&self.used) {
+            // The manifest might have been edited by hand
+            if !is_diagram_file(name) {
+                continue;
+            }
+            match fs::remove_file(self.dir.join(name)) {
+                Ok(()) => evicted += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if self.used != self.written {
+            let mut names: Vec<&str> = self.used.iter().map(String::as_str).collect();
+            names.sort_unstable();
+            let manifest: String = names.iter().flat_map(|name| [*name, "\n"]).collect();
+            self.create_dir()?;
+            let path = self.dir.join(MANIFEST);
+            fs::write(&path, manifest)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+        Ok(evicted)
+    }
+
+    /// The URL of the diagram file `name`, relative to the chapter at `chapter_path`
+    ///
+    /// Relative URLs keep the book relocatable, and its output independent of where it is built.
+    fn src(&self, name: &str, chapter_path: Option<&Path>) -> String {
+        let depth = chapter_path
+            .and_then(Path::parent)
+            .map_or(0, |dir| dir.components().count());
+        format!("{}{}/{name}", "../".repeat(depth), self.url_dir)
+    }
+}
+
+/// The `<img>` displaying `src`, which is an image of `svg`
+///
+/// It is self-closing, which is valid in both HTML and XHTML chapters.
+fn img(src: &str, svg: &str) -> String {
+    let alt = svg::title(svg)
+        .unwrap_or("Mermaid diagram")
+        .replace('"', "&quot;");
+    let dimensions = svg::dimensions(svg).map_or_else(String::new, |(width, height)| {
+        format!(r#" width="{width:.0}" height="{height:.0}" style="height: auto""#)
+    });
+    format!(r#"<img src="{src}" alt="{alt}" loading="lazy"{dimensions}/>"#)
+}
+
+/// Whether `name` is the name of a file written by [`DiagramFiles`]
+fn is_diagram_file(name: &str) -> bool {
+    name.strip_suffix(".svg")
+        .or_else(|| name.strip_suffix(".png"))
+        .or_else(|| name.strip_suffix(".pdf"))
+        .is_some_and(|hash| hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const SVG: &str = r#"<svg id="a" viewBox="0 0 200.4 100"><title>A &amp; B</title></svg>"#;
+
+    /// The name of the file `svg` is written to
+    fn svg_name(svg: &str) -> String {
+        format!("{}.svg", cache::digest(&[&svg::to_xml(svg)]))
+    }
+
+    /// The name of the file the PNG of `svg` is written to
+    fn png_name(svg: &str, device_scale_factor: f64) -> String {
+        format!(
+            "{}.png",
+            cache::digest(&[svg, &device_scale_factor.to_string()])
+        )
+    }
+
+    #[test]
+    fn writes_svg_files() {
+        let src = tempfile::tempdir().unwrap();
+        let mut files = DiagramFiles::new(src.path(), "html");
+
+        let img = files.write_svg(SVG, Some(Path::new("intro.md"))).unwrap();
+        let name = svg_name(SVG);
+        assert_eq!(
+            img,
+            format!(
+                r#"<img src="mermaid-ssr/html/{name}" alt="A &amp; B" loading="lazy" width="200" height="100" style="height: auto"/>"#
+            )
+        );
+        assert_eq!(
+            fs::read_to_string(src.path().join("mermaid-ssr/html").join(&name)).unwrap(),
+            svg::to_xml(SVG)
+        );
+
+        // Nested chapters reference the same file
+        let img = files
+            .write_svg(SVG, Some(Path::new("guide/setup/index.md")))
+            .unwrap();
+        assert!(img.starts_with(&format!(r#"<img src="../../mermaid-ssr/html/{name}""#)));
+    }
+
+    #[test]
+    fn writes_svg_files_as_xml() {
+        let src = tempfile::tempdir().unwrap();
+        let mut files = DiagramFiles::new(src.path(), "html");
+        let svg = r#"<svg id="a"><foreignObject><div><span class="nodeLabel">A&nbsp;B<br>C</span></div></foreignObject></svg>"#;
+
+        files.write_svg(svg, None).unwrap();
+        let picture = files
+            .write_png(svg, 1.0, true, None, || Ok(b"PNG".to_vec()))
+            .unwrap();
+        assert!(picture.contains(&svg_name(svg)));
+        assert_eq!(
+            fs::read_to_string(src.path().join("mermaid-ssr/html").join(svg_name(svg))).unwrap(),
+            r#"<svg xmlns="http://www.w3.org/2000/svg" id="a"><foreignObject><div xmlns="http://www.w3.org/1999/xhtml"><span class="nodeLabel">A&#xa0;B<br/>C</span></div></foreignObject></svg>"#
+        );
+    }
+
+    #[test]
+    fn writes_png_files() {
+        let src = tempfile::tempdir().unwrap();
+        let mut files = DiagramFiles::new(src.path(), "html");
+        let png = png_name(SVG, 2.0);
+
+        let img = files
+            .write_png(SVG, 2.0, false, None, || Ok(b"PNG".to_vec()))
+            .unwrap();
+        assert_eq!(
+            img,
+            format!(
+                r#"<img src="mermaid-ssr/html/{png}" alt="A &amp; B" loading="lazy" width="200" height="100" style="height: auto"/>"#
+            )
+        );
+        assert_eq!(
+            fs::read(src.path().join("mermaid-ssr/html").join(&png)).unwrap(),
+            b"PNG"
+        );
+
+        // Existing PNGs are not rasterized again
+        let picture = files
+            .write_png(SVG, 2.0, true, Some(Path::new("guide/intro.md")), || {
+                anyhow::bail!("rasterized again")
+            })
+            .unwrap();
+        let svg = svg_name(SVG);
+        assert!(picture.starts_with(&format!(
+            r#"<picture><source srcset="../mermaid-ssr/html/{svg}" type="image/svg+xml"/><img src="../mermaid-ssr/html/{png}""#
+        )));
+        assert!(picture.ends_with("/></picture>"));
+        assert!(src.path().join("mermaid-ssr/html").join(svg).exists());
+    }
+
+    #[test]
+    fn writes_pdf_files() {
+        let build = tempfile::tempdir().unwrap();
+        let mut files = DiagramFiles::in_build_dir(build.path());
+        let name = format!("{}.pdf", cache::digest(&[SVG]));
+
+        let image = files
+            .write_pdf(SVG, Some(Path::new("guide/intro.md")), || {
+                Ok(b"%PDF-1.4".to_vec())
+            })
+            .unwrap();
+        assert_eq!(image, format!("![A &amp; B](<../mermaid-ssr/{name}>)"));
+        assert_eq!(
+            fs::read(build.path().join("mermaid-ssr").join(name)).unwrap(),
+            b"%PDF-1.4"
+        );
+    }
+
+    #[test]
+    fn evicts_unused_files() {
+        let src = tempfile::tempdir().unwrap();
+        let dir = src.path().join("mermaid-ssr/html");
+        let svg = svg_name(SVG);
+        let mut files = DiagramFiles::new(src.path(), "html");
+        files.write_svg(SVG, None).unwrap();
+        files
+            .write_png(SVG, 1.0, false, None, || Ok(b"PNG".to_vec()))
+            .unwrap();
+        assert_eq!(files.evict_unused().unwrap(), 0);
+        assert_eq!(fs::read_to_string(dir.join(".gitignore")).unwrap(), "*\n");
+        // Files which this preprocessor did not write are left alone
+        let unlisted = format!("{}.svg", "0123456789abcdef".repeat(4));
+        fs::write(dir.join(&unlisted), "<svg/>").unwrap();
+        fs::write(dir.join("logo.svg"), "<svg/>").unwrap();
+
+        // Renderers do not remove each other's files
+        let mut other = DiagramFiles::new(src.path(), "epub");
+        other
+            .write_png(SVG, 1.0, false, None, || Ok(b"PNG".to_vec()))
+            .unwrap();
+        assert_eq!(other.evict_unused().unwrap(), 0);
+
+        let mut files = DiagramFiles::new(src.path(), "html");
+        files.write_svg(SVG, None).unwrap();
+        assert_eq!(files.evict_unused().unwrap(), 1);
+
+        let mut remaining: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            [".gitignore", ".manifest", &unlisted, &svg, "logo.svg"]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join(".manifest")).unwrap(),
+            format!("{svg}\n")
+        );
+        assert!(
+            src.path()
+                .join("mermaid-ssr/epub")
+                .join(png_name(SVG, 1.0))
+                .exists()
+        );
+    }
+}
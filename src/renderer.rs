@@ -1,19 +1,811 @@
-use std::{process::Command, sync::Arc};
+use std::{
+    ffi::OsStr,
+    fmt,
+    process::Command,
+    sync::{Arc, Condvar, Mutex, RwLock, mpsc},
+    time::{Duration, Instant},
+};
 
-use anyhow::{Result, bail};
-use escape_string::escape;
-use headless_chrome::{Browser, LaunchOptions, Tab};
+use anyhow::{Context, Result, anyhow, bail};
+use base64::Engine;
+use headless_chrome::{
+    Browser, LaunchOptions, Tab,
+    browser::tab::RequestPausedDecision,
+    protocol::cdp::{DOM, Emulation, Fetch, Network, Page, Runtime},
+    types::PrintToPdfOptions,
+};
 use serde_json::Value;
-use unescape::unescape;
+use url::Url;
 
-use crate::config::Config;
+use crate::config::{Config, RecycleAfter};
+
+/// The bundled mermaid.js payload
+pub(crate) const MERMAID_JS: &str = include_str!("../payload/mermaid.js");
+
+/// The page binding through which a tab reports the results of a batch
+const RENDERED_BINDING: &str = "mermaidRendered";
+
+/// A backend which renders mermaid diagrams to SVG
+///
+/// [`Mermaid`] is the default backend, which renders in a headless Chromium.
+pub trait DiagramRenderer: Send + Sync {
+    /// Renders the mermaid diagram `input` to an SVG
+    ///
+    /// Errors are handled according to [`crate::config::Config::on_error`].
+    fn render(&self, input: &str) -> Result<String>;
+
+    /// Renders several mermaid diagrams, returning their results in the same order as `inputs`
+    ///
+    /// Backends which can render a batch of diagrams more efficiently than one by one override
+    /// this.
+    fn render_many(&self, inputs: &[&str]) -> Vec<Result<String>> {
+        inputs.iter().map(|input| self.render(input)).collect()
+    }
+
+    /// Rasterizes a rendered `svg` to a PNG
+    ///
+    /// Only backends which render in a browser support this.
+    fn rasterize(&self, _svg: &str) -> Result<Vec<u8>> {
+        bail!("Rasterizing diagrams requires the chrome backend")
+    }
+
+    /// Prints a rendered `svg` to a PDF
+    ///
+    /// Only backends which render in a browser support this.
+    fn print_pdf(&self, _svg: &str) -> Result<Vec<u8>> {
+        bail!("Printing diagrams to PDF requires the chrome backend")
+    }
+}
+
+/// The error returned for a diagram which did not render within
+/// [`crate::config::Config::render_timeout`]
+///
+/// Such errors are handled according to [`crate::config::Config::on_timeout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderTimeout(pub Duration);
+
+impl fmt::Display for RenderTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timed out after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for RenderTimeout {}
 
 /// The Mermaid struct holds the embedded Chromium instance that is used to render Mermaid
 /// diagrams
+///
+/// If the browser crashes, it is relaunched transparently.
 #[derive(Clone)]
 pub struct Mermaid {
+    session: Arc<RwLock<Arc<Session>>>,
+    config: Arc<Config>,
+}
+
+/// A browser together with the tabs opened in it
+struct Session {
+    // Declared before `browser`, so that the tabs are closed while the browser is still reachable
+    tabs: TabPool,
     browser: Browser,
+}
+
+/// Initialized tabs which are ready to render diagrams
+///
+/// New tabs are opened on demand, up to `max_tabs`.
+struct TabPool {
+    state: Mutex<TabPoolState>,
+    available: Condvar,
+    max_tabs: usize,
+    prelude_script: String,
+    init_script: String,
+    timezone: Option<String>,
+    locale: Option<String>,
+    /// URL prefixes the page may load resources from
+    allow_network: Arc<[Url]>,
+    /// Number of screenshot pixels per CSS pixel, if diagrams are rasterized
+    device_scale_factor: Option<f64>,
+    launch_timeout: Duration,
+    render_timeout: Duration,
+    /// How often the browser is pinged while waiting for a batch, so that the connection to it
+    /// does not time out
+    keep_alive: Duration,
+    recycle_after: RecycleAfter,
+    /// Whether the browser outlives this process, in which case the tabs have to be closed explicitly
+    shared_browser: bool,
+}
+
+struct TabPoolState {
+    idle: Vec<RenderTab>,
+    open: usize,
+}
+
+/// An initialized tab
+struct RenderTab {
     tab: Arc<Tab>,
+    /// Number of diagrams rendered in this tab
+    renders: usize,
+    /// URLs the page was blocked from loading, which are yet to be reported
+    blocked: Arc<Mutex<Vec<String>>>,
+    /// Receives the results of each batch rendered in this tab
+    rendered: mpsc::Receiver<Value>,
+}
+
+/// The outcome of rendering a batch of diagrams in a single evaluation
+enum Batch {
+    /// The results of the diagrams up to and including the first which timed out
+    Rendered(Vec<Result<String>>),
+    /// The evaluation was abandoned after it blocked the page past the timeouts of all diagrams
+    Terminated,
+}
+
+/// A tab checked out of the [`TabPool`], which is returned to it on drop
+struct PooledTab<'a> {
+    pool: &'a TabPool,
+    tab: Option<RenderTab>,
+}
+
+impl TabPool {
+    fn new(browser: &Browser, config: &Config, shared_browser: bool) -> Result<Self> {
+        let pool = Self {
+            state: Mutex::new(TabPoolState {
+                idle: vec![],
+                open: 0,
+            }),
+            available: Condvar::new(),
+            max_tabs: config.jobs(),
+            prelude_script: config.build_prelude_script(),
+            init_script: config.build_mermaid_init_script(),
+            timezone: config.timezone.clone(),
+            locale: config.locale.clone(),
+            allow_network: config
+                .allow_network
+                .iter()
+                .map(|allowed| parse_allowed(allowed))
+                .collect::<Result<_>>()?,
+            device_scale_factor: config.rasterizes().then_some(config.device_scale_factor),
+            launch_timeout: config.launch_timeout(),
+            render_timeout: config.render_timeout(),
+            keep_alive: connection_timeout(config) / 2,
+            recycle_after: config.recycle_after,
+            shared_browser,
+        };
+        // Open the first tab eagerly, so that a broken setup is reported on initialization
+        let tab = pool.open_tab(browser)?;
+        let mut state = pool.state.lock().expect("tab pool is never poisoned");
+        state.idle.push(tab);
+        state.open = 1;
+        drop(state);
+        Ok(pool)
+    }
+
+    fn open_tab(&self, browser: &Browser) -> Result<RenderTab> {
+        let html_payload = include_str!("../payload/index.html");
+
+        let tab = browser.new_tab()?;
+        tab.set_default_timeout(self.launch_timeout);
+        // Format dates and numbers independently of the host
+        if let Some(timezone) = &self.timezone {
+            tab.call_method(Emulation::SetTimezoneOverride {
+                timezone_id: timezone.clone(),
+            })
+            .with_context(|| format!("Failed to emulate timezone {timezone:?}"))?;
+        }
+        if let Some(locale) = &self.locale {
+            tab.call_method(Emulation::SetLocaleOverride {
+                locale: Some(locale.clone()),
+            })
+            .with_context(|| format!("Failed to emulate locale {locale:?}"))?;
+        }
+        if let Some(device_scale_factor) = self.device_scale_factor {
+            tab.call_method(Emulation::SetDeviceMetricsOverride {
+                width: 0,
+                height: 0,
+                device_scale_factor,
+                mobile: false,
+                scale: None,
+                screen_width: None,
+                screen_height: None,
+                position_x: None,
+                position_y: None,
+                dont_set_visible_size: None,
+                screen_orientation: None,
+                viewport: None,
+                display_feature: None,
+                device_posture: None,
+            })?;
+            // Screenshots keep the transparent background of the SVGs
+            tab.call_method(Emulation::SetDefaultBackgroundColorOverride {
+                color: Some(DOM::RGBA {
+                    r: 0,
+                    g: 0,
+                    b: 0,
+                    a: Some(0.0),
+                }),
+            })?;
+        }
+        // Isolate the page from the network, apart from the allowlist
+        let blocked = Arc::new(Mutex::new(vec![]));
+        let (allow_network, blocked_urls) = (Arc::clone(&self.allow_network), Arc::clone(&blocked));
+        tab.enable_request_interception(Arc::new(
+            move |_transport, _session_id, event: Fetch::events::RequestPausedEvent| {
+                let url = event.params.request.url;
+                if is_allowed(&url, &allow_network) {
+                    return RequestPausedDecision::Continue(None);
+                }
+                blocked_urls
+                    .lock()
+                    .expect("blocked URLs are never poisoned")
+                    .push(url);
+                RequestPausedDecision::Fail(Fetch::FailRequest {
+                    request_id: event.params.request_id,
+                    error_reason: Network::ErrorReason::BlockedByClient,
+                })
+            },
+        ))?;
+        tab.enable_fetch(None, None)?;
+        // Exposed before navigating, so that the page gets it from the start
+        let (report, rendered) = mpsc::channel();
+        tab.expose_function(
+            RENDERED_BINDING,
+            Arc::new(move |call: Value| {
+                // The binding wraps its arguments into a JSON description of the call
+                let results = call
+                    .as_str()
+                    .and_then(|call| serde_json::from_str::<Value>(call).ok())
+                    .and_then(|mut call| call.get_mut("args")?.get_mut(0).map(Value::take))
+                    .unwrap_or_default();
+                // Nobody is waiting anymore once the tab is closed
+                let _ = report.send(results);
+            }),
+        )?;
+        tab.navigate_to(&format!("data:text/html;charset=utf-8,{html_payload}"))?;
+
+        // Set up the page before mermaid captures anything of it
+        if !self.prelude_script.is_empty() {
+            tab.evaluate(&self.prelude_script, false)?;
+        }
+        // Load mermaid library
+        tab.evaluate(MERMAID_JS, false)?;
+        // Initialize mermaid with configured options and set up render function
+        tab.evaluate(&self.init_script, false)?;
+
+        Ok(RenderTab {
+            tab,
+            renders: 0,
+            blocked,
+            rendered,
+        })
+    }
+
+    /// Why `tab` is due to be replaced by a fresh one, if it is
+    fn recycle_reason(&self, tab: &RenderTab) -> Option<String> {
+        const MB: f64 = 1024.0 * 1024.0;
+
+        let RecycleAfter { renders, heap_mb } = self.recycle_after;
+        if renders.is_some_and(|renders| tab.renders >= renders.get()) {
+            return Some(format!("{} renders", tab.renders));
+        }
+        let heap_mb = heap_mb?;
+        match tab.tab.call_method(Runtime::GetHeapUsage(None)) {
+            Ok(usage) if usage.used_size >= f64::from(heap_mb) * MB => Some(format!(
+                "its JS heap grew to {:.0} MB",
+                usage.used_size / MB
+            )),
+            Ok(_) => None,
+            Err(e) => {
+                log::debug!("Failed to query the JS heap usage of a tab: {e}");
+                None
+            }
+        }
+    }
+
+    /// Checks out an idle tab, opening a new one if none is idle and the limit is not reached yet
+    fn checkout(&self, browser: &Browser) -> Result<PooledTab<'_>> {
+        let mut state = self.state.lock().expect("tab pool is never poisoned");
+        loop {
+            if let Some(tab) = state.idle.pop() {
+                return Ok(PooledTab {
+                    pool: self,
+                    tab: Some(tab),
+                });
+            }
+            if state.open < self.max_tabs {
+                state.open += 1;
+                drop(state);
+                return match self.open_tab(browser) {
+                    Ok(tab) => Ok(PooledTab {
+                        pool: self,
+                        tab: Some(tab),
+                    }),
+                    Err(e) => {
+                        self.state.lock().expect("tab pool is never poisoned").open -= 1;
+                        self.available.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+            state = self
+                .available
+                .wait(state)
+                .expect("tab pool is never poisoned");
+        }
+    }
+}
+
+impl Drop for TabPool {
+    fn drop(&mut self) {
+        if !self.shared_browser {
+            return;
+        }
+        let state = self.state.get_mut().expect("tab pool is never poisoned");
+        for RenderTab { tab, .. } in state.idle.drain(..) {
+            if let Err(e) = tab.close(false) {
+                log::warn!("Failed to close tab in the shared browser: {e}");
+            }
+        }
+    }
+}
+
+impl PooledTab<'_> {
+    /// Takes the URLs the page was blocked from loading since the last call
+    fn take_blocked(&self) -> Vec<String> {
+        self.tab.as_ref().map_or_else(Vec::new, |tab| {
+            std::mem::take(&mut *tab.blocked.lock().expect("blocked URLs are never poisoned"))
+        })
+    }
+
+    /// Waits up to `timeout` for the results of the batch rendered in the tab
+    fn recv_rendered(&self, timeout: Duration) -> Result<Value, mpsc::RecvTimeoutError> {
+        self.tab
+            .as_ref()
+            .expect("tab is only taken on drop")
+            .rendered
+            .recv_timeout(timeout)
+    }
+
+    /// Returns the tab to the pool after `renders` renders, unless it is due to be recycled
+    fn release(mut self, renders: usize) {
+        let Some(tab) = self.tab.as_mut() else {
+            return;
+        };
+        tab.renders += renders;
+        if let Some(reason) = self.pool.recycle_reason(tab) {
+            log::debug!("Recycling a tab after {reason}");
+            self.discard();
+        }
+    }
+
+    /// Closes the tab instead of returning it to the pool, after it failed or is due to be recycled
+    fn discard(mut self) {
+        if let Some(RenderTab { tab, .. }) = self.tab.take() {
+            if let Err(e) = tab.close(false) {
+                log::debug!("Failed to close tab: {e}");
+            }
+            self.pool
+                .state
+                .lock()
+                .expect("tab pool is never poisoned")
+                .open -= 1;
+            self.pool.available.notify_one();
+        }
+    }
+}
+
+impl std::ops::Deref for PooledTab<'_> {
+    type Target = Tab;
+
+    fn deref(&self) -> &Tab {
+        &self.tab.as_ref().expect("tab is only taken on drop").tab
+    }
+}
+
+impl Drop for PooledTab<'_> {
+    fn drop(&mut self) {
+        if let Some(tab) = self.tab.take() {
+            self.pool
+                .state
+                .lock()
+                .expect("tab pool is never poisoned")
+                .idle
+                .push(tab);
+            self.pool.available.notify_one();
+        }
+    }
+}
+
+impl Session {
+    fn start(config: &Config) -> Result<Self> {
+        if let Some(ws_url) = remote_browser_ws_url(config)? {
+            log::info!("Connecting to the browser at {ws_url}");
+            let browser = Browser::connect_with_timeout(ws_url, connection_timeout(config))?;
+            let tabs = TabPool::new(&browser, config, true)?;
+            return Ok(Self { tabs, browser });
+        }
+
+        // Configure browser with timeout settings, optional custom chrome path and arguments
+        let mut launch_options_builder = LaunchOptions::default_builder();
+        launch_options_builder
+            .idle_browser_timeout(connection_timeout(config))
+            .sandbox(config.sandbox)
+            .args(config.chrome_args.iter().map(OsStr::new).collect());
+
+        if let Some(ref chrome_path) = config.chrome_path {
+            launch_options_builder.path(Some(chrome_path.into()));
+        }
+
+        let launch_options = launch_options_builder.build()?;
+
+        let browser = Browser::new(launch_options)?;
+        let tabs = TabPool::new(&browser, config, false)?;
+
+        Ok(Self { tabs, browser })
+    }
+
+    /// Renders diagrams as one batch in an idle tab, using a single evaluation
+    ///
+    /// Each diagram has its own timeout. A diagram which timed out ends its batch, and the
+    /// remaining diagrams continue in a fresh tab.
+    ///
+    /// Returns `Err` if the tab or the browser failed, which might succeed when retried, and
+    /// per diagram `Err`s for diagrams which failed to render, such as those with syntax errors
+    /// or which timed out.
+    fn render(&self, inputs: &[&str]) -> Result<Vec<Result<String>>> {
+        let mut rendered = Vec::with_capacity(inputs.len());
+        while rendered.len() < inputs.len() {
+            let batch = &inputs[rendered.len()..];
+            match self.render_batch(batch)? {
+                Batch::Rendered(results) => rendered.extend(results),
+                Batch::Terminated if batch.len() == 1 => {
+                    rendered.push(Err(RenderTimeout(self.tabs.render_timeout).into()));
+                }
+                // It is unknown which diagram blocked the page, so they are rendered one at a time
+                // for only that diagram to fail
+                Batch::Terminated => {
+                    for input in batch {
+                        rendered.extend(self.render(&[input])?);
+                    }
+                }
+            }
+        }
+        Ok(rendered)
+    }
+
+    /// Renders diagrams one after another in an idle tab, using a single evaluation
+    ///
+    /// The results end early with the first diagram which timed out.
+    fn render_batch(&self, inputs: &[&str]) -> Result<Batch> {
+        let timeout = self.tabs.render_timeout;
+        let timeout_ms = timeout.as_secs_f64() * 1000.0;
+        let batch_timeout = timeout.saturating_mul(u32::try_from(inputs.len()).unwrap_or(u32::MAX));
+        // The results are reported through a binding instead of awaiting the evaluation, as a
+        // call to the browser has to be answered before the connection times out
+        let script = format!(
+            "window.renderMany({}, {timeout_ms}).then((results) => window.{RENDERED_BINDING}(results), \
+             (error) => window.{RENDERED_BINDING}({{ error: String(error) }}));",
+            diagrams_json(inputs)
+        );
+        let tab = self.tabs.checkout(&self.browser)?;
+        let started = tab.call_method(Runtime::Evaluate {
+            expression: script,
+            return_by_value: Some(true),
+            generate_preview: None,
+            silent: Some(false),
+            await_promise: Some(false),
+            include_command_line_api: Some(false),
+            user_gesture: Some(false),
+            object_group: None,
+            context_id: None,
+            throw_on_side_effect: None,
+            // Terminates a first render which blocks the page before it yields, which the timer
+            // of `window.render` cannot interrupt
+            timeout: Some(timeout_ms),
+            disable_breaks: None,
+            repl_mode: None,
+            allow_unsafe_eval_blocked_by_csp: None,
+            unique_context_id: None,
+            serialization_options: None,
+        });
+        let rendered = match started {
+            // Thrown before the first render yielded, like a rejection of the batch
+            Ok(Runtime::EvaluateReturnObject {
+                exception_details: Some(exception),
+                ..
+            }) => Ok(Some(serde_json::json!({
+                "error": exception
+                    .exception
+                    .and_then(|exception| exception.description)
+                    .unwrap_or(exception.text),
+            }))),
+            Ok(_) => self.wait_rendered(&tab, batch_timeout),
+            Err(e) => Err(e),
+        };
+        log_blocked(inputs, tab.take_blocked());
+        // Neither an abandoned evaluation nor the timer of `window.render` cancels the render
+        // itself, which keeps the render queue of mermaid locked. Such tabs are therefore never
+        // returned to the pool.
+        let rendered = match rendered {
+            Ok(Some(rendered)) => rendered,
+            Ok(None) => {
+                tab.discard();
+                return Ok(Batch::Terminated);
+            }
+            Err(e) => {
+                tab.discard();
+                if is_terminated(&e.to_string()) {
+                    return Ok(Batch::Terminated);
+                }
+                return Err(e);
+            }
+        };
+
+        // Use proper JSON parsing instead of fragile string operations
+        let rendered: Vec<_> = match rendered {
+            Value::Array(rendered) if (1..=inputs.len()).contains(&rendered.len()) => rendered
+                .into_iter()
+                .map(|rendered| parse_rendered(rendered, timeout))
+                .collect(),
+            Value::Object(failed) if failed.contains_key("error") => {
+                let description = failed["error"].as_str().unwrap_or_default();
+                if is_terminated(description) {
+                    tab.discard();
+                    return Ok(Batch::Terminated);
+                }
+                tab.release(inputs.len());
+                return Ok(Batch::Rendered(fail_all(inputs, || {
+                    anyhow!("Failed to compile Mermaid diagram: {description}")
+                })));
+            }
+            other => {
+                tab.discard();
+                bail!("Unexpected return type from render: {other:?}");
+            }
+        };
+        if rendered.iter().any(is_timeout) {
+            tab.discard();
+        } else if rendered.len() < inputs.len() {
+            tab.discard();
+            bail!(
+                "Rendering stopped after {} of {} diagrams",
+                rendered.len(),
+                inputs.len()
+            );
+        } else {
+            tab.release(inputs.len());
+        }
+        Ok(Batch::Rendered(rendered))
+    }
+
+    /// Waits up to `timeout` for the results of the batch rendered in `tab`
+    ///
+    /// The browser is pinged meanwhile, so that the connection to it stays alive however long the
+    /// batch takes. Returns `None` if the batch did not finish in time.
+    fn wait_rendered(&self, tab: &PooledTab<'_>, timeout: Duration) -> Result<Option<Value>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match tab.recv_rendered(remaining.min(self.tabs.keep_alive)) {
+                Ok(rendered) => return Ok(Some(rendered)),
+                Err(mpsc::RecvTimeoutError::Timeout) if remaining <= self.tabs.keep_alive => {
+                    return Ok(None);
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    self.browser
+                        .get_version()
+                        .context("The browser stopped responding while rendering")?;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    bail!("The tab closed while rendering")
+                }
+            }
+        }
+    }
+
+    /// Rasterizes `svg` in an idle tab
+    fn rasterize(&self, svg: &str) -> Result<Vec<u8>> {
+        self.with_idle_tab(|tab| screenshot_svg(tab, svg))
+    }
+
+    /// Prints `svg` to a PDF in an idle tab
+    fn print_pdf(&self, svg: &str) -> Result<Vec<u8>> {
+        self.with_idle_tab(|tab| print_svg(tab, svg))
+    }
+
+    /// Runs `f` in an idle tab, which is discarded if `f` fails
+    fn with_idle_tab<T>(&self, f: impl FnOnce(&Tab) -> Result<T>) -> Result<T> {
+        let tab = self.tabs.checkout(&self.browser)?;
+        match f(&tab) {
+            Ok(result) => {
+                tab.release(0);
+                Ok(result)
+            }
+            Err(e) => {
+                tab.discard();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Shows `svg` at its natural size in the `#output` element of `tab`, returning the
+/// `[x, y, width, height]` of the element in CSS pixels
+fn show_svg(tab: &Tab, svg: &str) -> Result<[f64; 4]> {
+    let script = format!(
+        r"(() => {{
+    const output = document.getElementById('output');
+    output.innerHTML = {};
+    // The natural size of the diagram, not the width of the page
+    const svg = output.querySelector('svg');
+    const {{ width, height }} = svg?.viewBox.baseVal ?? {{}};
+    if (width && height) {{
+        svg.setAttribute('width', width);
+        svg.setAttribute('height', height);
+        svg.style.maxWidth = 'none';
+    }}
+    const rect = output.getBoundingClientRect();
+    return [rect.x, rect.y, rect.width, rect.height];
+}})()",
+        serde_json::to_string(svg)?
+    );
+    let rect = tab.evaluate(&script, false)?.value.unwrap_or_default();
+    serde_json::from_value(rect).context("Failed to measure the diagram")
+}
+
+/// Removes the SVG shown by [`show_svg`]
+fn clear_svg(tab: &Tab) -> Result<()> {
+    tab.evaluate("document.getElementById('output').replaceChildren()", false)?;
+    Ok(())
+}
+
+/// Takes a PNG screenshot of `svg`
+fn screenshot_svg(tab: &Tab, svg: &str) -> Result<Vec<u8>> {
+    let [x, y, width, height] = show_svg(tab, svg)?;
+    let screenshot = tab.call_method(Page::CaptureScreenshot {
+        format: Some(Page::CaptureScreenshotFormatOption::Png),
+        quality: None,
+        clip: Some(Page::Viewport {
+            x,
+            y,
+            width,
+            height,
+            scale: 1.0,
+        }),
+        from_surface: Some(true),
+        capture_beyond_viewport: Some(true),
+        optimize_for_speed: None,
+    })?;
+    clear_svg(tab)?;
+    Ok(base64::prelude::BASE64_STANDARD.decode(screenshot.data)?)
+}
+
+/// Prints `svg` to a single page PDF, which is exactly as large as the diagram
+fn print_svg(tab: &Tab, svg: &str) -> Result<Vec<u8>> {
+    const CSS_PIXELS_PER_INCH: f64 = 96.0;
+
+    let [_, _, width, height] = show_svg(tab, svg)?;
+    let pdf = tab.print_to_pdf(Some(PrintToPdfOptions {
+        print_background: Some(true),
+        paper_width: Some(width / CSS_PIXELS_PER_INCH),
+        paper_height: Some(height / CSS_PIXELS_PER_INCH),
+        margin_top: Some(0.0),
+        margin_bottom: Some(0.0),
+        margin_left: Some(0.0),
+        margin_right: Some(0.0),
+        // Rounding must not spill the diagram onto a second page
+        page_ranges: Some("1".to_string()),
+        ..PrintToPdfOptions::default()
+    }))?;
+    clear_svg(tab)?;
+    Ok(pdf)
+}
+
+/// Parses an entry of the network allowlist
+///
+/// The page is loaded from a `data:` URL, from which the browser never loads local files, so only
+/// HTTP(S) URLs can be allowed.
+fn parse_allowed(allowed: &str) -> Result<Url> {
+    let url =
+        Url::parse(allowed).with_context(|| format!("Invalid allow-network entry {allowed:?}"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("Invalid allow-network entry {allowed:?}: only HTTP(S) URLs can be allowed");
+    }
+    Ok(url)
+}
+
+/// Whether the page may load `url`, which it may only if the URL is inline or on the allowlist
+///
+/// An allowlist entry only matches URLs with the same scheme, host and port, whose path is the
+/// path of the entry or lies below it. URLs which cannot be parsed are never allowed.
+fn is_allowed(url: &str, allow_network: &[Url]) -> bool {
+    let Ok(url) = Url::parse(url) else {
+        return false;
+    };
+    url.scheme() == "data"
+        || allow_network.iter().any(|allowed| {
+            url.scheme() == allowed.scheme()
+                && url.host() == allowed.host()
+                && url.port_or_known_default() == allowed.port_or_known_default()
+                && is_below(url.path(), allowed.path())
+        })
+}
+
+/// Whether `path` is `prefix` or lies below it
+fn is_below(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/'))
+}
+
+/// Reports the URLs the page was blocked from loading while rendering `inputs`
+fn log_blocked(inputs: &[&str], blocked: Vec<String>) {
+    for url in blocked {
+        // Diagrams usually reference the resources they load verbatim
+        let referenced_by = inputs.iter().find(|input| {
+            input.contains(url.as_str()) || input.contains(url.trim_end_matches('/'))
+        });
+        match (referenced_by, inputs) {
+            (Some(input), _) | (None, [input]) => {
+                log::warn!("Blocked network access to {url} by diagram:\n{input}");
+            }
+            (None, _) => log::warn!(
+                "Blocked network access to {url} by one of these diagrams:\n{}",
+                inputs.join("\n\n")
+            ),
+        }
+    }
+}
+
+/// Interprets the result of `window.render` for a single diagram
+fn parse_rendered(rendered: Value, timeout: Duration) -> Result<String> {
+    let svg = match rendered {
+        Value::String(svg) => svg,
+        Value::Null => {
+            bail!("Failed to compile Mermaid diagram: render returned null");
+        }
+        Value::Object(result) if result.get("timedOut") == Some(&Value::Bool(true)) => {
+            bail!(RenderTimeout(timeout));
+        }
+        other => {
+            bail!("Unexpected return type from render: {other:?}");
+        }
+    };
+
+    if svg.is_empty() {
+        bail!("Failed to compile Mermaid diagram: empty result");
+    }
+
+    Ok(svg)
+}
+
+/// The `[id, source]` pairs of `inputs` as a JSON array, which is also a JS literal
+///
+/// Unlike escaping the sources into JS strings by hand, this keeps every character intact.
+fn diagrams_json(inputs: &[&str]) -> String {
+    let diagrams: Vec<(String, &str)> = inputs
+        .iter()
+        .map(|input| (format!("mermaid-diagram-{}", fxhash::hash64(input)), *input))
+        .collect();
+    serde_json::to_string(&diagrams).expect("strings always serialize")
+}
+
+/// Whether `rendered` failed with a [`RenderTimeout`]
+fn is_timeout(rendered: &Result<String>) -> bool {
+    rendered
+        .as_ref()
+        .is_err_and(|e| e.downcast_ref::<RenderTimeout>().is_some())
+}
+
+/// How long the connection to the browser may stay silent before it is considered dead
+///
+/// This also limits how long a call to the browser waits for its response, so it has to leave
+/// room for an evaluation of at least one diagram.
+fn connection_timeout(config: &Config) -> Duration {
+    config
+        .idle_timeout()
+        .max(config.render_timeout() + config.launch_timeout())
+}
+
+/// The same error for each of `inputs`
+fn fail_all(inputs: &[&str], error: impl Fn() -> anyhow::Error) -> Vec<Result<String>> {
+    inputs.iter().map(|_| Err(error())).collect()
 }
 
 impl Mermaid {
@@ -24,6 +816,8 @@ impl Mermaid {
 
     /// Initializes Mermaid with a configuration object
     ///
+    /// Up to [`Config::jobs`] tabs are opened on demand to render diagrams concurrently.
+    ///
     /// # Arguments
     /// * `config` - Configuration for the renderer
     ///
@@ -36,74 +830,179 @@ impl Mermaid {
     ///     .expect("Failed to initialize");
     /// ```
     pub fn try_init_with_config(config: &Config) -> Result<Self> {
-        // Configure browser with timeout settings and optional custom chrome path
-        let mut launch_options_builder = LaunchOptions::default_builder();
-        launch_options_builder.idle_browser_timeout(config.timeout);
-
-        if let Some(ref chrome_path) = config.chrome_path {
-            launch_options_builder.path(Some(chrome_path.into()));
-        }
-
-        let launch_options = launch_options_builder.build()?;
+        let session = Session::start(config)?;
+        Ok(Self {
+            session: Arc::new(RwLock::new(Arc::new(session))),
+            config: Arc::new(config.clone()),
+        })
+    }
 
-        let browser = Browser::new(launch_options)?;
-        let mermaid_js = include_str!("../payload/mermaid.js");
-        let html_payload = include_str!("../payload/index.html");
+    /// Renders a diagram
+    ///
+    /// Fails with [`RenderTimeout`] if the diagram does not render within
+    /// [`Config::render_timeout`], in which case its rendering is cancelled.
+    /// If the tab or the browser fails, rendering is retried up to [`Config::render_retries`]
+    /// times in a fresh tab, after relaunching the browser if it is no longer responding.
+    ///
+    /// # Example:
+    /// ```no_run
+    /// # use mdbook_mermaid_ssr::renderer::Mermaid;
+    /// let mermaid = Mermaid::try_init().expect("Failed to initialize");
+    /// let svg = mermaid.render("graph TB\na-->b").expect("Unable to render!");
+    /// ```
+    pub fn render(&self, input: &str) -> Result<String> {
+        let mut rendered = self.with_retries(|session| session.render(&[input]))?;
+        rendered.pop().expect("one result per diagram")
+    }
 
-        let tab = browser.new_tab()?;
-        tab.set_default_timeout(config.timeout);
-        tab.navigate_to(&format!("data:text/html;charset=utf-8,{html_payload}"))?;
+    /// Renders several diagrams with as few round trips to the browser as possible
+    ///
+    /// The diagrams are rendered one after another in batches, each of which is rendered in a
+    /// single tab, and their results are returned in the same order as `inputs`.
+    ///
+    /// # Example:
+    /// ```no_run
+    /// # use mdbook_mermaid_ssr::renderer::Mermaid;
+    /// let mermaid = Mermaid::try_init().expect("Failed to initialize");
+    /// let svgs = mermaid.render_many(&["graph TB\na-->b", "graph TB\nb-->c"]);
+    /// ```
+    #[must_use]
+    pub fn render_many(&self, inputs: &[&str]) -> Vec<Result<String>> {
+        if inputs.is_empty() {
+            return vec![];
+        }
+        match self.with_retries(|session| session.render(inputs)) {
+            Ok(rendered) => rendered,
+            Err(e) => fail_all(inputs, || anyhow!("{e:#}")),
+        }
+    }
 
-        // Load mermaid library
-        tab.evaluate(mermaid_js, false)?;
-        // Initialize mermaid with configured options and set up render function
-        let init_script = config.build_mermaid_init_script();
-        tab.evaluate(&init_script, false)?;
+    /// Rasterizes a rendered `svg` to a PNG, at [`Config::device_scale_factor`] pixels per CSS
+    /// pixel
+    ///
+    /// The tabs only apply the device scale factor if [`Config::output`] is `png` or `picture`.
+    pub fn rasterize(&self, svg: &str) -> Result<Vec<u8>> {
+        self.with_retries(|session| session.rasterize(svg))
+    }
 
-        Ok(Self { browser, tab })
+    /// Prints a rendered `svg` to a PDF with a single page, which is exactly as large as the
+    /// diagram
+    pub fn print_pdf(&self, svg: &str) -> Result<Vec<u8>> {
+        self.with_retries(|session| session.print_pdf(svg))
     }
 
-    /// Renders a diagram
+    /// Renders the mermaid diagram `input` to a PDF, for print pipelines which prefer vector
+    /// figures to SVGs
     ///
     /// # Example:
     /// ```no_run
     /// # use mdbook_mermaid_ssr::renderer::Mermaid;
     /// let mermaid = Mermaid::try_init().expect("Failed to initialize");
-    /// let svg = mermaid.render("graph TB\na-->b").expect("Unable to render!");
+    /// let pdf = mermaid.render_pdf("graph TB\na-->b").expect("Failed to render");
+    /// std::fs::write("diagram.pdf", pdf).expect("Failed to write");
     /// ```
-    pub fn render(&self, input: &str) -> Result<String> {
-        let id = fxhash::hash64(input);
-        // Call the async render function and await its result
-        let script = format!(
-            "(async () => {{ return await window.render('mermaid-diagram-{id}', '{}'); }})()",
-            escape(input)
-        );
-        let data = self.tab.evaluate(&script, true)?;
+    pub fn render_pdf(&self, input: &str) -> Result<Vec<u8>> {
+        let svg = self.render(input)?;
+        self.print_pdf(&svg)
+    }
 
-        // Use proper JSON parsing instead of fragile string operations
-        let svg = match data.value {
-            Some(Value::String(s)) => {
-                // Unescape the string value if needed
-                unescape(&s).unwrap_or(s)
-            }
-            Some(Value::Null) | None => {
-                bail!("Failed to compile Mermaid diagram: render returned null");
-            }
-            Some(other) => {
-                bail!("Unexpected return type from render: {other:?}");
+    /// Runs `render`, retrying up to [`Config::render_retries`] times if the tab or the browser
+    /// failed
+    fn with_retries<T>(&self, render: impl Fn(&Session) -> Result<T>) -> Result<T> {
+        let retries = self.config.render_retries;
+        let mut attempt = 0;
+        loop {
+            let session = self.session();
+            match render(&session) {
+                Ok(rendered) => return Ok(rendered),
+                Err(e) if attempt < retries => {
+                    attempt += 1;
+                    log::warn!("Rendering failed, retrying ({attempt}/{retries}): {e}");
+                    self.recover(&session)?;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("Failed to render Mermaid diagram after {attempt} retries")
+                    });
+                }
             }
-        };
+        }
+    }
 
-        if svg.is_empty() {
-            bail!("Failed to compile Mermaid diagram: empty result");
+    /// Relaunches the browser of `failed` if it is no longer responding
+    ///
+    /// A failed tab is discarded by [`Session::render`] already, so that the next attempt opens a
+    /// fresh one.
+    fn recover(&self, failed: &Arc<Session>) -> Result<()> {
+        if failed.browser.get_version().is_ok() {
+            return Ok(());
+        }
+        let mut session = self.session.write().expect("session is never poisoned");
+        // Another thread might have relaunched the browser in the meantime
+        if Arc::ptr_eq(&session, failed) {
+            log::warn!("The browser is no longer responding, relaunching it");
+            *session =
+                Arc::new(Session::start(&self.config).context("Failed to relaunch the browser")?);
         }
+        Ok(())
+    }
 
-        Ok(svg)
+    fn session(&self) -> Arc<Session> {
+        Arc::clone(&self.session.read().expect("session is never poisoned"))
     }
+
     /// Gives access to the underlying browser instance
+    ///
+    /// The browser is relaunched if it crashes, so the handle only refers to the browser at the
+    /// time of the call.
     #[must_use]
-    pub fn browser(&self) -> &Browser {
-        &self.browser
+    pub fn browser(&self) -> Browser {
+        self.session().browser.clone()
+    }
+}
+
+impl DiagramRenderer for Mermaid {
+    fn render(&self, input: &str) -> Result<String> {
+        Mermaid::render(self, input)
+    }
+
+    fn render_many(&self, inputs: &[&str]) -> Vec<Result<String>> {
+        Mermaid::render_many(self, inputs)
+    }
+
+    fn rasterize(&self, svg: &str) -> Result<Vec<u8>> {
+        Mermaid::rasterize(self, svg)
+    }
+
+    fn print_pdf(&self, svg: &str) -> Result<Vec<u8>> {
+        Mermaid::print_pdf(self, svg)
+    }
+}
+
+/// Whether an evaluation failed because it exceeded its timeout and was terminated
+fn is_terminated(message: &str) -> bool {
+    message.contains("Execution was terminated")
+}
+
+/// The websocket debugging URL of an already running browser, if one is configured
+///
+/// `browser-ws-url` takes precedence over `remote-debugging-port`.
+fn remote_browser_ws_url(config: &Config) -> Result<Option<String>> {
+    if let Some(ref ws_url) = config.browser_ws_url {
+        return Ok(Some(ws_url.clone()));
+    }
+    let Some(port) = config.remote_debugging_port else {
+        return Ok(None);
+    };
+    let version_url = format!("http://127.0.0.1:{port}/json/version");
+    let version = ureq::get(&version_url)
+        .call()
+        .and_then(|mut response| response.body_mut().read_to_string())
+        .with_context(|| format!("Failed to query the browser at {version_url}"))?;
+    let version: Value = serde_json::from_str(&version)?;
+    match version.get("webSocketDebuggerUrl") {
+        Some(Value::String(ws_url)) => Ok(Some(ws_url.clone())),
+        _ => bail!("{version_url} did not report a webSocketDebuggerUrl"),
     }
 }
 
@@ -157,7 +1056,7 @@ impl Oxfmt {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::SecurityLevel;
+    use crate::config::{Output, SecurityLevel};
 
     use super::*;
 
@@ -178,6 +1077,186 @@ grph TB
         assert!(rendered.is_err());
     }
 
+    #[test]
+    fn render_many() {
+        let mermaid = Mermaid::try_init().unwrap();
+        assert!(mermaid.render_many(&[]).is_empty());
+
+        let rendered =
+            mermaid.render_many(&["graph TB\na-->b", "grph TB\na-->b", "graph TB\na-->b"]);
+        assert_eq!(rendered.len(), 3);
+        assert!(rendered[0].as_ref().unwrap().starts_with("<svg"));
+        assert_eq!(
+            rendered[1].as_ref().unwrap_err().to_string(),
+            "Failed to compile Mermaid diagram: render returned null"
+        );
+        assert_eq!(rendered[0].as_ref().unwrap(), rendered[2].as_ref().unwrap());
+    }
+
+    #[test]
+    fn diagrams_json_keeps_every_character() {
+        let input = "graph TD\nA[\"C:\\path\\n\\u0041 \\'\"] --> B[漢字 😀 שלום]\u{2028}";
+        let json = diagrams_json(&[input]);
+        let diagrams: Vec<(String, String)> = serde_json::from_str(&json).unwrap();
+        assert_eq!(diagrams[0].1, input);
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let allow_network = ["http://localhost:8080", "https://cdn.example/icons"]
+            .map(|allowed| parse_allowed(allowed).unwrap());
+        let is_allowed = |url: &str| is_allowed(url, &allow_network);
+        assert!(is_allowed("data:image/png;base64,AAAA"));
+        assert!(!is_allowed("file:///etc/passwd"));
+        assert!(!is_allowed("https://example.com/logo.png"));
+
+        assert!(is_allowed("http://localhost:8080/logo.png"));
+        assert!(!is_allowed("http://localhost:8080.evil.com/"));
+        assert!(!is_allowed("http://localhost:8081/logo.png"));
+        assert!(!is_allowed("https://localhost:8080/logo.png"));
+        assert!(!is_allowed("http://user@localhost.evil.com:8080/"));
+
+        assert!(is_allowed("https://cdn.example/icons"));
+        assert!(is_allowed("https://cdn.example:443/icons/logo.svg"));
+        assert!(!is_allowed("https://cdn.example/icons-evil/logo.svg"));
+        assert!(!is_allowed(
+            "https://cdn.example.attacker.net/icons/logo.svg"
+        ));
+    }
+
+    #[test]
+    fn rejects_local_allow_network_entries() {
+        for allowed in ["assets/", "file:///book/assets/"] {
+            assert!(parse_allowed(allowed).is_err(), "{allowed}");
+        }
+    }
+
+    #[test]
+    fn isolates_the_network() {
+        let config = Config {
+            allow_network: vec!["http://127.0.0.1:9/allowed/".to_string()],
+            ..Config::default()
+        };
+        let mermaid = Mermaid::try_init_with_config(&config).unwrap();
+
+        let session = mermaid.session();
+        let state = session.tabs.state.lock().unwrap();
+        let tab = &state.idle[0];
+        tab.tab
+            .evaluate(
+                "Promise.all(['http://example.com/blocked.png', 'http://127.0.0.1:9/allowed/image.png']
+                    .map((url) => fetch(url).catch(() => null)))",
+                true,
+            )
+            .unwrap();
+        assert_eq!(
+            *tab.blocked.lock().unwrap(),
+            ["http://example.com/blocked.png"]
+        );
+    }
+
+    #[test]
+    fn renders_unicode_and_backslashes() {
+        let mermaid = Mermaid::try_init().unwrap();
+        for label in [
+            "漢字かな한글",
+            "😀 👩‍👩‍👧 𝄞",
+            "שלום עולם مرحبا",
+            r"C:\path\n \u0041 \x41",
+        ] {
+            let svg = mermaid
+                .render(&format!("graph TD\nA[\"{label}\"] --> B"))
+                .unwrap();
+            assert!(svg.contains(label), "{label} is missing in {svg}");
+        }
+    }
+
+    #[test]
+    fn recovers_from_closed_tabs() {
+        let mermaid = Mermaid::try_init().unwrap();
+        mermaid.render("graph TB\na-->b").unwrap();
+
+        for tab in mermaid.browser().get_tabs().lock().unwrap().iter() {
+            tab.close(false).unwrap();
+        }
+        mermaid.render("graph TB\na-->b").unwrap();
+    }
+
+    #[test]
+    fn recycles_tabs() {
+        let config = Config {
+            recycle_after: RecycleAfter {
+                renders: std::num::NonZeroUsize::new(1),
+                heap_mb: Some(1024),
+            },
+            ..Config::default()
+        };
+        let mermaid = Mermaid::try_init_with_config(&config).unwrap();
+        mermaid.render("graph TB\na-->b").unwrap();
+        mermaid.render("graph TB\na-->b").unwrap();
+
+        // Each tab was closed after its render
+        let session = mermaid.session();
+        let state = session.tabs.state.lock().unwrap();
+        assert_eq!(state.open, 0);
+        assert!(state.idle.is_empty());
+    }
+
+    #[test]
+    fn emulates_timezone_and_locale() {
+        let config = Config {
+            timezone: Some("Europe/Berlin".to_string()),
+            locale: Some("de-DE".to_string()),
+            ..Config::default()
+        };
+        let mermaid = Mermaid::try_init_with_config(&config).unwrap();
+
+        let session = mermaid.session();
+        let state = session.tabs.state.lock().unwrap();
+        let emulated = state.idle[0]
+            .tab
+            .evaluate(
+                "`${Intl.DateTimeFormat().resolvedOptions().timeZone} ${navigator.language}`",
+                false,
+            )
+            .unwrap()
+            .value;
+        assert_eq!(emulated, Some("Europe/Berlin de-DE".into()));
+    }
+
+    #[test]
+    fn rasterizes_svgs() {
+        let config = Config {
+            output: Output::Png,
+            device_scale_factor: 2.0,
+            ..Config::default()
+        };
+        let mermaid = Mermaid::try_init_with_config(&config).unwrap();
+        let svg = mermaid.render("graph TB\na-->b").unwrap();
+        let (width, _) = crate::svg::dimensions(&svg).unwrap();
+
+        let png = mermaid.rasterize(&svg).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        // The width in the IHDR chunk is in device pixels
+        let png_width = u32::from_be_bytes(png[16..20].try_into().unwrap());
+        assert!(
+            (f64::from(png_width) - 2.0 * width).abs() <= 2.0,
+            "{png_width} px for {width} CSS px"
+        );
+    }
+
+    #[test]
+    fn renders_pdfs() {
+        let mermaid = Mermaid::try_init().unwrap();
+        let pdf = mermaid.render_pdf("graph TB\na-->b").unwrap();
+        assert!(pdf.starts_with(b"%PDF-"));
+        // A single page
+        assert!(
+            pdf.windows(b"/Count 1".len())
+                .any(|window| window == b"/Count 1")
+        );
+    }
+
     #[test]
     fn test_with_config() {
         let mut config = Config::default();
@@ -253,4 +1332,48 @@ grph TB
         let fmt = Oxfmt::format(svg).expect("Failed to format SVG");
         insta::assert_snapshot!("security_level_sandbox", fmt);
     }
+
+    #[test]
+    fn test_remote_browser_ws_url() {
+        let mut config = Config::default();
+        assert_eq!(remote_browser_ws_url(&config).unwrap(), None);
+
+        // Nothing listens on this port anymore
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        config.remote_debugging_port = Some(port);
+        let err = remote_browser_ws_url(&config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Failed to query the browser at http://127.0.0.1:{port}/json/version")
+        );
+
+        config.browser_ws_url = Some("ws://chrome:9222/devtools/browser/0d1f".to_string());
+        assert_eq!(
+            remote_browser_ws_url(&config).unwrap().as_deref(),
+            Some("ws://chrome:9222/devtools/browser/0d1f")
+        );
+    }
+
+    #[test]
+    fn renders_chapter_in_one_batch() {
+        let mermaid = Mermaid::try_init_with_config(&Config::default()).unwrap();
+        let inputs: Vec<String> = (0..20).map(|i| format!("graph TB\na-->b{i}")).collect();
+        let inputs: Vec<&str> = inputs.iter().map(String::as_str).collect();
+
+        let rendered = mermaid.render_many(&inputs);
+        assert!(rendered.iter().all(Result::is_ok));
+
+        // The binding counts its calls, once per batch
+        let session = mermaid.session();
+        let tab = session.tabs.checkout(&session.browser).unwrap();
+        let batches = tab
+            .evaluate(&format!("window.{RENDERED_BINDING}.lastSeq"), false)
+            .unwrap()
+            .value;
+        assert_eq!(batches, Some(serde_json::json!(1)));
+    }
 }
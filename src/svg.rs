@@ -0,0 +1,455 @@
+//! Post-processing of rendered SVGs
+
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::sync::LazyLock;
+
+use regex::{Captures, Regex};
+
+/// The `id` of the root `<svg>` element
+static ROOT_ID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"<svg\b[^>]*?\sid="([^"]+)""#).expect("valid regex"));
+
+/// Every `id` attribute
+static ID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\sid="([^"]+)""#).expect("valid regex"));
+
+/// Everything which references an element by its ID, or declares one
+static REFERENCE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(concat!(
+        r#"(?P<attr>\sid=")(?P<id>[^"]+)""#,
+        r#"|(?P<url>url\(#)(?P<url_id>[^)\s"']+)"#,
+        r##"|(?P<href>\s(?:xlink:)?href="#)(?P<href_id>[^"]+)""##,
+        r#"|(?P<aria>\saria-(?:labelledby|describedby|controls|owns|activedescendant|details|errormessage|flowto)=")(?P<aria_ids>[^"]*)""#,
+        r"|(?P<style><style\b[^>]*>)(?P<css>[\s\S]*?)</style>",
+    ))
+    .expect("valid regex")
+});
+
+/// The `viewBox` of the root `<svg>` element
+static VIEW_BOX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"<svg\b[^>]*?\sviewBox="\s*[-\d.]+[\s,]+[-\d.]+[\s,]+([\d.]+)[\s,]+([\d.]+)\s*""#)
+        .expect("valid regex")
+});
+
+/// The accessible title of the diagram
+static TITLE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<title\b[^>]*>([^<]*)</title>").expect("valid regex"));
+
+/// CSS ID selectors
+static CSS_ID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"#(-?[A-Za-z_][\w-]*)").expect("valid regex"));
+
+/// HTML elements which have no contents and no end tag
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Elements whose contents are not markup in HTML
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style"];
+
+/// HTML entities which diagram labels commonly contain, but XML does not predefine
+const HTML_ENTITIES: &[(&str, u32)] = &[
+    ("nbsp", 0xa0),
+    ("copy", 0xa9),
+    ("reg", 0xae),
+    ("deg", 0xb0),
+    ("middot", 0xb7),
+    ("times", 0xd7),
+    ("ndash", 0x2013),
+    ("mdash", 0x2014),
+    ("lsquo", 0x2018),
+    ("rsquo", 0x2019),
+    ("ldquo", 0x201c),
+    ("rdquo", 0x201d),
+    ("bull", 0x2022),
+    ("hellip", 0x2026),
+    ("euro", 0x20ac),
+    ("trade", 0x2122),
+    ("larr", 0x2190),
+    ("rarr", 0x2192),
+];
+
+const SVG_NAMESPACE: &str = "http://www.w3.org/2000/svg";
+const XLINK_NAMESPACE: &str = "http://www.w3.org/1999/xlink";
+const XHTML_NAMESPACE: &str = "http://www.w3.org/1999/xhtml";
+
+/// Moves all element IDs of `svg` into `namespace`, so that several copies of the same diagram on
+/// one page do not collide
+///
+/// The root element is renamed to `namespace`. Mermaid derives most other IDs and the scoped CSS
+/// from the root ID, so those are renamed along with it. The remaining IDs are prefixed with
+/// `namespace`. Only IDs and their references via `url(#…)`, `href`, `aria-*` attributes or CSS
+/// are rewritten, so text which happens to contain an ID is left alone.
+pub fn namespace_ids(svg: &str, namespace: &str) -> String {
+    let Some(root) = ROOT_ID
+        .captures(svg)
+        .map(|captures| captures[1].to_string())
+    else {
+        return svg.to_string();
+    };
+
+    let declared: HashSet<&str> = ID
+        .captures_iter(svg)
+        .filter_map(|captures| captures.get(1))
+        .map(|id| id.as_str())
+        .collect();
+    let rename = |id: &str| {
+        if id.contains(root.as_str()) {
+            id.replace(root.as_str(), namespace)
+        } else if declared.contains(id) {
+            format!("{namespace}-{id}")
+        } else {
+            id.to_string()
+        }
+    };
+    REFERENCE
+        .replace_all(svg, |captures: &Captures| {
+            if let (Some(prefix), Some(id)) = (captures.name("attr"), captures.name("id")) {
+                format!("{}{}\"", prefix.as_str(), rename(id.as_str()))
+            } else if let (Some(prefix), Some(id)) = (captures.name("url"), captures.name("url_id"))
+            {
+                format!("{}{}", prefix.as_str(), rename(id.as_str()))
+            } else if let (Some(prefix), Some(id)) =
+                (captures.name("href"), captures.name("href_id"))
+            {
+                format!("{}{}\"", prefix.as_str(), rename(id.as_str()))
+            } else if let (Some(prefix), Some(ids)) =
+                (captures.name("aria"), captures.name("aria_ids"))
+            {
+                let ids: Vec<String> = ids.as_str().split_whitespace().map(rename).collect();
+                format!("{}{}\"", prefix.as_str(), ids.join(" "))
+            } else if let (Some(style), Some(css)) = (captures.name("style"), captures.name("css"))
+            {
+                let css = CSS_ID
+                    .replace_all(css.as_str(), |id: &Captures| format!("#{}", rename(&id[1])));
+                format!("{}{css}</style>", style.as_str())
+            } else {
+                captures[0].to_string()
+            }
+        })
+        .into_owned()
+}
+
+/// The width and height of `svg` in pixels, as given by its `viewBox`
+pub fn dimensions(svg: &str) -> Option<(f64, f64)> {
+    let captures = VIEW_BOX.captures(svg)?;
+    Some((captures[1].parse().ok()?, captures[2].parse().ok()?))
+}
+
+/// The title of `svg`, such as the `accTitle` of a mermaid diagram, still escaped as XML text
+pub fn title(svg: &str) -> Option<&str> {
+    TITLE
+        .captures(svg)
+        .and_then(|captures| captures.get(1))
+        .map(|title| title.as_str().trim())
+        .filter(|title| !title.is_empty())
+}
+
+/// Re-serializes `svg` as well-formed XML, for XHTML documents such as the chapters of EPUBs
+///
+/// Mermaid serializes its SVGs as HTML, so the HTML labels within `<foreignObject>` contain void
+/// elements like `<br>`, and possibly unquoted attributes or HTML entities like `&nbsp;`. Those
+/// are rewritten to their XML equivalents, and the namespaces XHTML requires are declared.
+#[must_use]
+pub fn to_xml(svg: &str) -> String {
+    let mut xml = String::with_capacity(svg.len());
+    let mut open: Vec<&str> = vec![];
+    let mut rest = svg;
+    while let Some(start) = rest.find('<') {
+        push_escaped(&mut xml, &rest[..start], false);
+        rest = &rest[start..];
+
+        // Comments, CDATA sections, processing instructions and doctypes are copied verbatim
+        if let Some((_, end)) = [
+            ("<!--", "-->"),
+            ("<![CDATA[", "]]>"),
+            ("<?", ">"),
+            ("<!", ">"),
+        ]
+        .into_iter()
+        .find(|(start, _)| rest.starts_with(start))
+        {
+            let len = rest.find(end).map_or(rest.len(), |index| index + end.len());
+            xml.push_str(&rest[..len]);
+            rest = &rest[len..];
+            continue;
+        }
+
+        if let Some(tag) = rest.strip_prefix("</") {
+            let Some(end) = tag.find('>') else {
+                break;
+            };
+            let name = tag[..end].trim();
+            // HTML parsers ignore the end tags of void elements
+            if !VOID_ELEMENTS.contains(&name) {
+                if let Some(index) = open.iter().rposition(|&open| open == name) {
+                    open.truncate(index);
+                }
+                xml.push_str("</");
+                xml.push_str(name);
+                xml.push('>');
+            }
+            rest = &tag[end + 1..];
+            continue;
+        }
+
+        let Some((tag, len)) = Tag::parse(rest) else {
+            xml.push_str("&lt;");
+            rest = &rest[1..];
+            continue;
+        };
+        rest = &rest[len..];
+        let namespace = match open.last() {
+            None if tag.name == "svg" => Some(SVG_NAMESPACE),
+            Some(&"foreignObject") => Some(XHTML_NAMESPACE),
+            _ => None,
+        };
+        let xlink = open.is_empty() && svg.contains("xlink:");
+        tag.push_xml(&mut xml, namespace, xlink);
+        if tag.self_closing || VOID_ELEMENTS.contains(&tag.name) {
+            continue;
+        }
+        open.push(tag.name);
+
+        if RAW_TEXT_ELEMENTS.contains(&tag.name) {
+            let end = rest.find(&format!("</{}", tag.name)).unwrap_or(rest.len());
+            push_escaped(&mut xml, &rest[..end], false);
+            rest = &rest[end..];
+        }
+    }
+    push_escaped(&mut xml, rest, false);
+    xml
+}
+
+/// A start tag of an element
+struct Tag<'a> {
+    name: &'a str,
+    /// Attributes in the order of their first occurrence, without a value if they have none
+    attributes: Vec<(&'a str, Option<&'a str>)>,
+    self_closing: bool,
+}
+
+impl<'a> Tag<'a> {
+    /// Parses the start tag `markup` starts with, returning it and its length
+    fn parse(markup: &'a str) -> Option<(Self, usize)> {
+        let is_name_end = |c: char| c.is_whitespace() || matches!(c, '=' | '>' | '/');
+        let tag = markup.strip_prefix('<')?;
+        if !tag.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return None;
+        }
+        let name_len = tag.find(is_name_end)?;
+        let mut start_tag = Self {
+            name: &tag[..name_len],
+            attributes: vec![],
+            self_closing: false,
+        };
+        let mut rest = &tag[name_len..];
+        loop {
+            rest = rest.trim_start();
+            if let Some(end) = rest.strip_prefix("/>") {
+                start_tag.self_closing = true;
+                return Some((start_tag, markup.len() - end.len()));
+            }
+            if let Some(end) = rest.strip_prefix('>') {
+                return Some((start_tag, markup.len() - end.len()));
+            }
+            if let Some(after_slash) = rest.strip_prefix('/') {
+                rest = after_slash;
+                continue;
+            }
+
+            let name_len = rest.find(is_name_end)?;
+            let name = &rest[..name_len];
+            rest = rest[name_len..].trim_start();
+            let mut value = None;
+            if let Some(after_equals) = rest.strip_prefix('=') {
+                rest = after_equals.trim_start();
+                let (quote, unquoted) = match rest.chars().next()? {
+                    quote @ ('"' | '\'') => (Some(quote), &rest[1..]),
+                    _ => (None, rest),
+                };
+                let len = match quote {
+                    Some(quote) => unquoted.find(quote)?,
+                    None => unquoted.find(|c: char| c.is_whitespace() || c == '>')?,
+                };
+                value = Some(&unquoted[..len]);
+                rest = &unquoted[len + usize::from(quote.is_some())..];
+            }
+            // HTML parsers ignore repeated attributes
+            if !start_tag.attributes.iter().any(|&(other, _)| other == name) {
+                start_tag.attributes.push((name, value));
+            }
+        }
+    }
+
+    /// Appends the tag as XML, declaring the default `namespace` and the `xlink` namespace if
+    /// requested and not declared yet
+    fn push_xml(&self, xml: &mut String, namespace: Option<&str>, xlink: bool) {
+        xml.push('<');
+        xml.push_str(self.name);
+        let declares = |name: &str| self.attributes.iter().any(|&(other, _)| other == name);
+        if let Some(namespace) = namespace.filter(|_| !declares("xmlns")) {
+            xml.push_str(" xmlns=\"");
+            xml.push_str(namespace);
+            xml.push('"');
+        }
+        if xlink && !declares("xmlns:xlink") {
+            xml.push_str(" xmlns:xlink=\"");
+            xml.push_str(XLINK_NAMESPACE);
+            xml.push('"');
+        }
+        for &(name, value) in &self.attributes {
+            xml.push(' ');
+            xml.push_str(name);
+            xml.push_str("=\"");
+            push_escaped(xml, value.unwrap_or_default(), true);
+            xml.push('"');
+        }
+        xml.push_str(if self.self_closing || VOID_ELEMENTS.contains(&self.name) {
+            "/>"
+        } else {
+            ">"
+        });
+    }
+}
+
+/// Appends `text` to `xml`, escaping what XML does not allow in text or, with `attribute`, in
+/// attribute values
+///
+/// Character and entity references stay intact, but HTML entities become character references.
+fn push_escaped(xml: &mut String, text: &str, attribute: bool) {
+    let mut rest = text;
+    while let Some(index) = rest.find(['&', '<', '"']) {
+        xml.push_str(&rest[..index]);
+        rest = &rest[index..];
+        if rest.starts_with('<') {
+            xml.push_str("&lt;");
+        } else if rest.starts_with('"') {
+            xml.push_str(if attribute { "&quot;" } else { "\"" });
+        } else {
+            let reference = rest[1..]
+                .find(';')
+                .map(|end| &rest[1..=end])
+                .filter(|name| {
+                    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'#')
+                });
+            match reference {
+                Some(name)
+                    if matches!(name, "amp" | "lt" | "gt" | "quot" | "apos")
+                        || is_character_reference(name) =>
+                {
+                    xml.push('&');
+                    xml.push_str(name);
+                    xml.push(';');
+                }
+                Some(name) => {
+                    if let Some((_, code)) =
+                        HTML_ENTITIES.iter().find(|&&(entity, _)| entity == name)
+                    {
+                        let _ = write!(xml, "&#x{code:x};");
+                    } else {
+                        xml.push_str("&amp;");
+                        xml.push_str(name);
+                        xml.push(';');
+                    }
+                }
+                None => {
+                    xml.push_str("&amp;");
+                    rest = &rest[1..];
+                    continue;
+                }
+            }
+            rest = &rest[rest.find(';').expect("references end with a semicolon") + 1..];
+            continue;
+        }
+        rest = &rest[1..];
+    }
+    xml.push_str(rest);
+}
+
+/// Whether `name` is the name of a character reference, such as `#160` or `#xa0`
+fn is_character_reference(name: &str) -> bool {
+    match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+        Some(hex) => !hex.is_empty() && hex.bytes().all(|b| b.is_ascii_hexdigit()),
+        None => name
+            .strip_prefix('#')
+            .is_some_and(|dec| !dec.is_empty() && dec.bytes().all(|b| b.is_ascii_digit())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn renames_root_and_derived_ids() {
+        let svg = r#"<svg id="mermaid-diagram-1" aria-labelledby="chart-title-mermaid-diagram-1"><style>#mermaid-diagram-1 .node{fill:#fff;}</style><marker id="mermaid-diagram-1_flowchart-v2-pointEnd"/><path marker-end="url(#mermaid-diagram-1_flowchart-v2-pointEnd)"/></svg>"#;
+        assert_eq!(
+            namespace_ids(svg, "mermaid-diagram-2"),
+            r#"<svg id="mermaid-diagram-2" aria-labelledby="chart-title-mermaid-diagram-2"><style>#mermaid-diagram-2 .node{fill:#fff;}</style><marker id="mermaid-diagram-2_flowchart-v2-pointEnd"/><path marker-end="url(#mermaid-diagram-2_flowchart-v2-pointEnd)"/></svg>"#
+        );
+    }
+
+    #[test]
+    fn prefixes_other_ids_and_their_references() {
+        let svg = r##"<svg id="root"><style>#root .label{color:#333;} #arrowhead{fill:#fff;}</style><defs><marker id="arrowhead"/><linearGradient id="gradient"/></defs><path id="L_A_B_0" marker-end="url(#arrowhead)" fill="url(#gradient)"/><use xlink:href="#L_A_B_0" href="#L_A_B_0"/><g aria-describedby="L_A_B_0 unknown" aria-roledescription="arrowhead"><text>#arrowhead</text></g></svg>"##;
+        assert_eq!(
+            namespace_ids(svg, "ns"),
+            r##"<svg id="ns"><style>#ns .label{color:#333;} #ns-arrowhead{fill:#fff;}</style><defs><marker id="ns-arrowhead"/><linearGradient id="ns-gradient"/></defs><path id="ns-L_A_B_0" marker-end="url(#ns-arrowhead)" fill="url(#ns-gradient)"/><use xlink:href="#ns-L_A_B_0" href="#ns-L_A_B_0"/><g aria-describedby="ns-L_A_B_0 unknown" aria-roledescription="arrowhead"><text>#arrowhead</text></g></svg>"##
+        );
+    }
+
+    #[test]
+    fn leaves_text_mentioning_the_root_id_alone() {
+        // mmdc always uses this root ID
+        let svg = r#"<svg id="my-svg" aria-roledescription="flowchart-v2"><style>#my-svg{font-size:16px;}</style><g id="my-svg-flowchart-A-0"><span class="nodeLabel">Render my-svg to #my-svg</span></g><path marker-end="url(#my-svg-flowchart-A-0)"/></svg>"#;
+        assert_eq!(
+            namespace_ids(svg, "ns"),
+            r#"<svg id="ns" aria-roledescription="flowchart-v2"><style>#ns{font-size:16px;}</style><g id="ns-flowchart-A-0"><span class="nodeLabel">Render my-svg to #my-svg</span></g><path marker-end="url(#ns-flowchart-A-0)"/></svg>"#
+        );
+    }
+
+    #[test]
+    fn test_dimensions() {
+        assert_eq!(
+            dimensions(
+                r#"<svg id="a" width="100%" viewBox="-8 -8 523.5 174" style="max-width: 523.5px;">"#
+            ),
+            Some((523.5, 174.0))
+        );
+        assert_eq!(dimensions(r#"<svg width="100%"><path/></svg>"#), None);
+    }
+
+    #[test]
+    fn test_title() {
+        assert_eq!(
+            title(r#"<svg><title id="chart-title-a">Login &amp; logout</title></svg>"#),
+            Some("Login &amp; logout")
+        );
+        assert_eq!(title("<svg><title></title></svg>"), None);
+    }
+
+    #[test]
+    fn serializes_html_labels_as_xml() {
+        let svg = r##"<svg id="a" width="100%"><style>#a .label>span{color:#333;}</style><use xlink:href="#b"/><foreignObject width="80" height="24"><div style="display: table-cell;"><span class=nodeLabel>A&nbsp;&amp;&nbsp;B<br>C &lt;D&gt; &copy;&unknown; R&D</span><img src="x.png" alt='"quoted"' hidden></img></div></foreignObject></svg>"##;
+        assert_eq!(
+            to_xml(svg),
+            r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" id="a" width="100%"><style>#a .label>span{color:#333;}</style><use xlink:href="#b"/><foreignObject width="80" height="24"><div xmlns="http://www.w3.org/1999/xhtml" style="display: table-cell;"><span class="nodeLabel">A&#xa0;&amp;&#xa0;B<br/>C &lt;D&gt; &#xa9;&amp;unknown; R&amp;D</span><img src="x.png" alt="&quot;quoted&quot;" hidden=""/></div></foreignObject></svg>"##
+        );
+    }
+
+    #[test]
+    fn keeps_xml_intact() {
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" id="a"><!-- <br> --><foreignObject><div xmlns="http://www.w3.org/1999/xhtml">A<br/>B&#160;&#xa0;</div></foreignObject></svg>"#;
+        assert_eq!(to_xml(svg), svg);
+    }
+
+    #[test]
+    fn leaves_svgs_without_root_id_untouched() {
+        let svg = r#"<svg><path id="a"/></svg>"#;
+        assert_eq!(namespace_ids(svg, "ns"), svg);
+    }
+}
@@ -39,6 +39,13 @@ fn ensure_binary_built() {
     });
 }
 
+fn binary_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("target")
+        .join("debug")
+        .join("mdbook-mermaid-ssr")
+}
+
 fn build_book(book_name: &str) -> PathBuf {
     ensure_binary_built();
 
@@ -48,10 +55,7 @@ fn build_book(book_name: &str) -> PathBuf {
         fs::remove_dir_all(&output_dir).expect("Failed to clean output directory");
     }
 
-    let binary_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-        .join("target")
-        .join("debug")
-        .join("mdbook-mermaid-ssr");
+    let binary_path = binary_path();
 
     let path_env = std::env::var("PATH").unwrap_or_default();
     let binary_dir = binary_path.parent().unwrap();
@@ -80,6 +84,24 @@ fn extract_main_content(html: &str) -> &str {
         .expect("Failed to find </main> tag")
 }
 
+#[test]
+fn test_supports_does_not_need_a_browser() {
+    ensure_binary_built();
+
+    // Without PATH, no browser can be found, so this only succeeds if none is launched
+    let supports = |renderer: &str| {
+        Command::new(binary_path())
+            .arg("supports")
+            .arg(renderer)
+            .env_clear()
+            .status()
+            .expect("Failed to run mdbook-mermaid-ssr supports")
+            .success()
+    };
+    assert!(supports("html"), "html should be supported");
+    assert!(!supports("markdown"), "markdown should not be supported");
+}
+
 #[test]
 fn test_book_builds() {
     BUILD_SIMPLE_BOOK.call_once(|| {
@@ -203,3 +225,22 @@ fn test_config_theme_forest() {
 
     insta::assert_snapshot!("theme_forest", content);
 }
+
+#[test]
+fn test_reproducible_builds() {
+    let read_chapter = || {
+        let output = build_book("reproducible");
+        fs::read_to_string(output.join("chapter.html")).expect("Failed to read chapter.html")
+    };
+
+    let first = read_chapter();
+    assert!(
+        first.contains("<svg"),
+        "Should contain rendered mermaid diagrams"
+    );
+    let second = read_chapter();
+    assert!(
+        extract_main_content(&first) == extract_main_content(&second),
+        "Building the same book twice should produce identical diagrams"
+    );
+}